@@ -0,0 +1,220 @@
+use std::collections::{HashMap, HashSet};
+
+use proc_macro2::{TokenStream as TokenStreamInternal, TokenTree, Group};
+use syn::{visit::Visit, visit_mut::VisitMut, Block, Expr, ExprClosure, ExprForLoop, ExprIf, ExprLet, ExprMacro, ExprMatch, ExprPath, ExprWhile, FieldValue, Pat, PatIdent, Stmt};
+use quote::quote;
+
+/// Replaces the free names in `expr` that are in `bindings`. Names
+/// bound inside the expression, by a closure, `let`, `match`, `for`,
+/// `if let` or `while let`, aren't replaced where they're in scope.
+/// Inside macro calls, where the tokens can't be parsed, names are
+/// replaced unless they're bound around the call.
+pub fn bind_names(expr: &Expr, bindings: &HashMap<String, TokenStreamInternal>) -> TokenStreamInternal {
+    let mut expr = expr.clone();
+    Binder { bindings, scopes: vec![] }.visit_expr_mut(&mut expr);
+    quote! { #expr }
+}
+
+struct Binder<'a> {
+    bindings: &'a HashMap<String, TokenStreamInternal>,
+    /// The names bound by each enclosing scope, innermost last.
+    scopes: Vec<HashSet<String>>,
+}
+
+impl Binder<'_> {
+    fn binding(&self, name: &str) -> Option<&TokenStreamInternal> {
+        if self.scopes.iter().any(|scope| scope.contains(name)) {
+            return None;
+        }
+        self.bindings.get(name)
+    }
+
+    /// Visits `visit` with the names in `pats` in scope.
+    fn scoped<'p>(&mut self, pats: impl IntoIterator<Item = &'p Pat>, visit: impl FnOnce(&mut Self)) {
+        let mut names = PatNames(HashSet::new());
+        for pat in pats {
+            names.visit_pat(pat);
+        }
+        self.scopes.push(names.0);
+        visit(self);
+        self.scopes.pop();
+    }
+
+    /// Visits the condition of an `if` or `while`, and the block it
+    /// guards, with the names an `if let` or `while let` binds in
+    /// scope for the block.
+    fn visit_condition(&mut self, cond: &mut Expr, block: &mut Block) {
+        match cond {
+            Expr::Let(ExprLet { pat, expr, .. }) => {
+                self.visit_expr_mut(expr);
+                self.scoped([&*pat], |binder| binder.visit_block_mut(block));
+            },
+            cond => {
+                self.visit_expr_mut(cond);
+                self.visit_block_mut(block);
+            },
+        }
+    }
+}
+
+impl VisitMut for Binder<'_> {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Path(ExprPath { qself: None, path, .. }) => {
+                if let Some(ident) = path.get_ident() {
+                    if let Some(binding) = self.binding(&ident.to_string()) {
+                        *expr = Expr::Verbatim(binding.clone());
+                    }
+                }
+            },
+            Expr::Closure(ExprClosure { inputs, body, .. }) => {
+                self.scoped(inputs.iter(), |binder| binder.visit_expr_mut(body));
+            },
+            Expr::ForLoop(ExprForLoop { pat, expr: iterable, body, .. }) => {
+                self.visit_expr_mut(iterable);
+                self.scoped([&*pat], |binder| binder.visit_block_mut(body));
+            },
+            Expr::Match(ExprMatch { expr: scrutinee, arms, .. }) => {
+                self.visit_expr_mut(scrutinee);
+                for arm in arms {
+                    self.scoped([&arm.pat], |binder| {
+                        if let Some((_, guard)) = &mut arm.guard {
+                            binder.visit_expr_mut(guard);
+                        }
+                        binder.visit_expr_mut(&mut arm.body);
+                    });
+                }
+            },
+            Expr::If(ExprIf { cond, then_branch, else_branch, .. }) => {
+                self.visit_condition(cond, then_branch);
+                if let Some((_, else_branch)) = else_branch {
+                    self.visit_expr_mut(else_branch);
+                }
+            },
+            Expr::While(ExprWhile { cond, body, .. }) => {
+                self.visit_condition(cond, body);
+            },
+            Expr::Macro(ExprMacro { mac, .. }) => {
+                let bindings: HashMap<String, TokenStreamInternal> = self.bindings.iter()
+                    .filter(|(name, _)| self.binding(name).is_some())
+                    .map(|(name, binding)| (name.clone(), binding.clone()))
+                    .collect();
+                mac.tokens = bind_tokens(mac.tokens.clone(), &bindings);
+            },
+            _ => syn::visit_mut::visit_expr_mut(self, expr),
+        }
+    }
+
+    fn visit_block_mut(&mut self, block: &mut Block) {
+        self.scopes.push(HashSet::new());
+        for stmt in &mut block.stmts {
+            match stmt {
+                Stmt::Local(local) => {
+                    if let Some((_, init)) = &mut local.init {
+                        self.visit_expr_mut(init);
+                    }
+                    let mut names = PatNames(HashSet::new());
+                    names.visit_pat(&local.pat);
+                    self.scopes.last_mut().unwrap().extend(names.0);
+                },
+                Stmt::Item(_) => {},
+                Stmt::Expr(expr) | Stmt::Semi(expr, _) => self.visit_expr_mut(expr),
+            }
+        }
+        self.scopes.pop();
+    }
+
+    fn visit_field_value_mut(&mut self, field: &mut FieldValue) {
+        self.visit_expr_mut(&mut field.expr);
+        if field.colon_token.is_none() && matches!(field.expr, Expr::Verbatim(_)) {
+            field.colon_token = Some(Default::default());
+        }
+    }
+}
+
+/// Collects the names a pattern binds.
+struct PatNames(HashSet<String>);
+
+impl<'ast> Visit<'ast> for PatNames {
+    fn visit_pat_ident(&mut self, pat: &'ast PatIdent) {
+        self.0.insert(pat.ident.to_string());
+        syn::visit::visit_pat_ident(self, pat);
+    }
+}
+
+/// Replaces the names in `tokens` that are in `bindings`, skipping
+/// ones used as a member (`a.name`) or a path segment (`name::A`).
+fn bind_tokens(tokens: TokenStreamInternal, bindings: &HashMap<String, TokenStreamInternal>) -> TokenStreamInternal {
+    let trees: Vec<TokenTree> = tokens.into_iter().collect();
+    let mut result = TokenStreamInternal::new();
+    for (index, tree) in trees.iter().enumerate() {
+        match tree {
+            TokenTree::Group(group) => {
+                let mut new_group = Group::new(group.delimiter(), bind_tokens(group.stream(), bindings));
+                new_group.set_span(group.span());
+                result.extend([TokenTree::Group(new_group)]);
+            },
+            TokenTree::Ident(ident) => {
+                let member = index > 0 && matches!(&trees[index - 1], TokenTree::Punct(punct) if punct.as_char() == '.');
+                let path = matches!(trees.get(index + 1), Some(TokenTree::Punct(punct)) if punct.as_char() == ':');
+                match bindings.get(&ident.to_string()) {
+                    Some(binding) if !member && !path => result.extend(binding.clone()),
+                    _ => result.extend([tree.clone()]),
+                }
+            },
+            _ => result.extend([tree.clone()]),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use quote::ToTokens;
+
+    use super::*;
+
+    fn bound(expr: &str) -> String {
+        let bindings = HashMap::from([
+            ("x".to_string(), quote! { (*a) }),
+            ("y".to_string(), quote! { (*b) }),
+        ]);
+        bind_names(&syn::parse_str(expr).unwrap(), &bindings).to_string()
+    }
+
+    fn tokens(expr: &str) -> String {
+        syn::parse_str::<Expr>(expr).unwrap().into_token_stream().to_string()
+    }
+
+    #[test]
+    fn test_bind_free_names() {
+        assert_eq!(bound("x + y.len() + z"), tokens("(*a) + (*b).len() + z"));
+        assert_eq!(bound("self.x + x::MAX"), tokens("self.x + x::MAX"));
+        assert_eq!(bound("Pair { x, y: y + 1 }"), tokens("Pair { x: (*a), y: (*b) + 1 }"));
+    }
+
+    #[test]
+    fn test_bind_skips_closure_params() {
+        assert_eq!(bound("y.iter().map(|x| x + 1).sum::<u8>() + x"), tokens("(*b).iter().map(|x| x + 1).sum::<u8>() + (*a)"));
+        assert_eq!(bound("y.iter().map(|&(x, _): &(u8, u8)| x).count()"), tokens("(*b).iter().map(|&(x, _): &(u8, u8)| x).count()"));
+    }
+
+    #[test]
+    fn test_bind_skips_shadowed_locals() {
+        assert_eq!(bound("{ let z = x; let x = x + 1; x * z }"), tokens("{ let z = (*a); let x = (*a) + 1; x * z }"));
+        assert_eq!(bound("{ let w = { let x = 1; x }; w + x }"), tokens("{ let w = { let x = 1; x }; w + (*a) }"));
+    }
+
+    #[test]
+    fn test_bind_skips_pattern_bindings() {
+        assert_eq!(bound("match y { Some(x) => x, None => x }"), tokens("match (*b) { Some(x) => x, None => (*a) }"));
+        assert_eq!(bound("if let Some(x) = y { x } else { x }"), tokens("if let Some(x) = (*b) { x } else { (*a) }"));
+        assert_eq!(bound("{ let mut n = 0; for x in y { n += x; } n + x }"), tokens("{ let mut n = 0; for x in (*b) { n += x; } n + (*a) }"));
+    }
+
+    #[test]
+    fn test_bind_in_macros() {
+        assert_eq!(bound("format!(\"{}\", x)"), tokens("format!(\"{}\", (*a))"));
+        assert_eq!(bound("y.iter().map(|x| vec![x]).count()"), tokens("(*b).iter().map(|x| vec![x]).count()"));
+    }
+}
@@ -0,0 +1,341 @@
+use proc_macro2::{TokenStream as TokenStreamInternal, Ident, Span};
+use syn::{DeriveInput, spanned::Spanned, DataEnum, Fields};
+use quote::quote;
+
+use crate::{bitpattern::{Range, Token, RawMappingValue, BitPattern, FixedBits, compute_mapping, find_bit_pattern, range_expr}, common::{generics, generics_names, where_clause_with, sign_extend_constraints, field_types, byte_array_tokens, range_width_checks}, decisiontree::DecisionTree, transform::decode_tokens};
+
+const GENERIC_FAILURE: &str = r#"#[derive(BitDecode)] expects an attribute of the form #[bitpattern("11010[a:0-2]0110", a=foo)]"#;
+
+pub fn expand_bit_decode(ast: &DeriveInput) -> syn::Result<TokenStreamInternal> {
+    let type_ident = &ast.ident;
+    let type_name = ast.ident.to_string();
+    let mut width_checks = vec![];
+    let mut generic_width_checks = vec![];
+    let (decoders, max_bits) = match &ast.data {
+        syn::Data::Struct(data_struct) => {
+            if let Some(pattern) = find_bit_pattern(&ast.attrs, &ast.attrs, &data_struct.fields)? {
+                let (items, statements) = range_width_checks(ast, &type_name, &data_struct.fields, &pattern)?;
+                width_checks.extend(items);
+                generic_width_checks.extend(statements);
+                let decoder = decode_variant(&type_name, &quote! { #type_ident }, &data_struct.fields, &pattern)?;
+                (vec![decoder], pattern.bits())
+            } else {
+                return Err(syn::Error::new(ast.ident.span(), GENERIC_FAILURE))
+            }
+        },
+        syn::Data::Enum(DataEnum { variants, .. }) => {
+            let mut decoders = vec![];
+            let mut max_bits = 0;
+            for variant in variants {
+                if let Some(pattern) = find_bit_pattern(&ast.attrs, &variant.attrs, &variant.fields)? {
+                    let variant_ident = &variant.ident;
+                    let target = format!("{}::{}", type_name, variant_ident);
+                    let (items, statements) = range_width_checks(ast, &target, &variant.fields, &pattern)?;
+                    width_checks.extend(items);
+                    generic_width_checks.extend(statements);
+                    let mut decoder = decode_variant(&target, &quote! { #type_ident::#variant_ident }, &variant.fields, &pattern)?;
+                    decoder.name = Some(variant_ident.clone());
+                    decoders.push(decoder);
+                    max_bits = usize::max(max_bits, pattern.bits());
+                } else {
+                    return Err(syn::Error::new(variant.span(), GENERIC_FAILURE))
+                }
+            }
+            check_overlaps(&decoders)?;
+            (decoders, max_bits)
+        },
+        syn::Data::Union(_) => return Err(syn::Error::new(ast.span(), "#[derive(BitDecode)] is not supported for untagged unions.")),
+    };
+    let max_bytes = (max_bits + 7) / 8;
+    let decode_fns = {
+        let mut decode_fns = vec![];
+        for (index, decoder) in decoders.iter().enumerate() {
+            let ident = Ident::new(&format!("____decode_{}", index), Span::call_site());
+            let body = &decoder.body;
+            decode_fns.push(quote! {
+                let #ident = || -> core::result::Result<Option<Self>, ::decree::Error> {
+                    #body
+                };
+            });
+        }
+        decode_fns
+    };
+    let (tree, length_tree) = {
+        let fixed_bits: Vec<FixedBits> = decoders.iter().map(|decoder| decoder.fixed_bits.clone()).collect();
+        let mut candidates: Vec<usize> = (0..decoders.len()).collect();
+        candidates.sort_by_key(|index| -decoders[*index].priority);
+        let tree = DecisionTree::build(&fixed_bits, candidates);
+        let decode = |index: usize, decoder: &VariantDecoder| {
+            let ident = Ident::new(&format!("____decode_{}", index), Span::call_site());
+            let bits = decoder.bits;
+            quote! {
+                if let Some(value) = #ident()? {
+                    return Ok((value, #bits));
+                }
+            }
+        };
+        let length = |index: usize, decoder: &VariantDecoder| {
+            let bits = decoder.bits;
+            if decoder.checked {
+                let ident = Ident::new(&format!("____decode_{}", index), Span::call_site());
+                quote! {
+                    if #ident()?.is_some() {
+                        return Ok(#bits);
+                    }
+                }
+            } else {
+                quote! { return Ok(#bits); }
+            }
+        };
+        (tree_tokens(&tree, &decoders, &decode), tree_tokens(&tree, &decoders, &length))
+    };
+    let generics = generics(ast);
+    let generics_names = generics_names(ast);
+    let extra_constraints = decoders.iter()
+        .flat_map(|decoder| decoder.constraints.clone())
+        .collect();
+    let where_clause = where_clause_with(ast, &quote! { ::decree::BitSink + ::core::default::Default }, extra_constraints)?;
+    let fill_buffer = quote! {
+        let bits = usize::min(len, #max_bits);
+        let mut buffer = [0u8; #max_bytes];
+        {
+            let mut sink = ::decree::LittleEndian::<&[u8]>::with_bits(&mut buffer[..], bits)?;
+            ::decree::BitSource::write(source, &mut sink, start, bits, 0)?;
+        }
+    };
+    Ok(quote! {
+        impl #generics ::decree::BitDecode for #type_ident #generics_names #where_clause {
+            fn decode_with_length(source: &impl ::decree::BitSource, start: usize, len: usize) -> core::result::Result<(Self, usize), ::decree::Error> {
+                #(#generic_width_checks)*
+                #fill_buffer
+
+                #(#decode_fns)*
+
+                #tree
+
+                Err(::decree::Error::no_matching_pattern(#type_name, len))
+            }
+
+            fn decode_length(source: &impl ::decree::BitSource, start: usize, len: usize) -> core::result::Result<usize, ::decree::Error> {
+                #fill_buffer
+
+                #(#decode_fns)*
+
+                #length_tree
+
+                Err(::decree::Error::no_matching_pattern(#type_name, len))
+            }
+        }
+
+        #(#width_checks)*
+    })
+}
+
+struct VariantDecoder {
+    name: Option<Ident>,
+    priority: i64,
+    bits: usize,
+    fixed_bits: FixedBits,
+    body: TokenStreamInternal,
+    /// Whether the variant has requirements, which can only be
+    /// checked by decoding it.
+    checked: bool,
+    constraints: Vec<TokenStreamInternal>,
+}
+
+fn check_overlaps(decoders: &[VariantDecoder]) -> syn::Result<()> {
+    let mut errors: Option<syn::Error> = None;
+    for (index, decoder) in decoders.iter().enumerate() {
+        for other in &decoders[(index + 1)..] {
+            if decoder.priority == other.priority && decoder.fixed_bits.overlaps(&other.fixed_bits) {
+                if let (Some(name), Some(other_name)) = (&decoder.name, &other.name) {
+                    for (ident, overlapping) in [(name, other_name), (other_name, name)] {
+                        let error = syn::Error::new(
+                            ident.span(),
+                            format!("The bit pattern for {} overlaps with the one for {}. Give one of them a higher priority (e.g. priority = 1) to decode matching inputs as that variant.", ident, overlapping)
+                        );
+                        match &mut errors {
+                            Some(errors) => errors.combine(error),
+                            None => errors = Some(error),
+                        }
+                    }
+                }
+            }
+        }
+    }
+    match errors {
+        Some(errors) => Err(errors),
+        None => Ok(()),
+    }
+}
+
+/// The code for `tree`, with `matched` giving what to do when the
+/// input matches a candidate's fixed bits.
+fn tree_tokens(tree: &DecisionTree, decoders: &[VariantDecoder], matched: &dyn Fn(usize, &VariantDecoder) -> TokenStreamInternal) -> TokenStreamInternal {
+    match tree {
+        DecisionTree::Leaf(candidates) => {
+            let mut checks = vec![];
+            for index in candidates {
+                let decoder = &decoders[*index];
+                let bits = decoder.bits;
+                let mask = byte_array_tokens(&decoder.fixed_bits.mask);
+                let value = byte_array_tokens(&decoder.fixed_bits.value);
+                let action = matched(*index, decoder);
+                checks.push(quote! {
+                    if bits >= #bits && ::decree::matches_pattern(&buffer, &#mask, &#value) {
+                        #action
+                    }
+                });
+            }
+            quote! { #(#checks)* }
+        },
+        DecisionTree::Branch { bit, zero, one } => {
+            let byte = bit / 8;
+            let shift = bit % 8;
+            let zero = tree_tokens(zero, decoders, matched);
+            let one = tree_tokens(one, decoders, matched);
+            quote! {
+                if (buffer[#byte] >> #shift) & 1 == 1 {
+                    #one
+                } else {
+                    #zero
+                }
+            }
+        },
+    }
+}
+
+fn decode_variant(target: &str, constructor: &TokenStreamInternal, fields: &Fields, pattern: &BitPattern) -> syn::Result<VariantDecoder> {
+    let mapping = compute_mapping(fields, pattern)?;
+    let referenced = pattern.referenced_values();
+    let mut constraints = sign_extend_constraints(fields, pattern)?;
+    let (declarations, values) = {
+        let mut declarations = vec![];
+        let mut values = vec![];
+        let mut index = 0;
+        for field in fields {
+            let ty = &field.ty;
+            let (name, new_ident) = if let Some(ident) = &field.ident {
+                let new_ident = Ident::new_raw(&format!("____{}", ident), ident.span());
+                values.push(quote! { #ident: #new_ident });
+                (RawMappingValue::Name(ident.to_string()), new_ident)
+            } else {
+                let new_ident = Ident::new_raw(&format!("_____{}", index), field.span());
+                values.push(quote! { #new_ident });
+                (RawMappingValue::Index(index), new_ident)
+            };
+            if referenced.contains(&name) && pattern.codecs.contains_key(&name) {
+                declarations.push(quote! { let mut #new_ident = ::core::default::Default::default(); });
+            } else if referenced.contains(&name) {
+                declarations.push(quote! { let mut #new_ident: #ty = ::core::default::Default::default(); });
+            } else if let Some(Some(default)) = pattern.skipped.get(&name) {
+                declarations.push(quote! { let #new_ident: #ty = #default; });
+            } else {
+                constraints.push(quote! { #ty: ::core::default::Default });
+                declarations.push(quote! { let #new_ident: #ty = ::core::default::Default::default(); });
+            }
+            index += 1;
+        }
+        for (ident, _) in &pattern.computed {
+            if !referenced.contains(&RawMappingValue::Name(ident.to_string())) {
+                continue;
+            }
+            let new_ident = Ident::new_raw(&format!("____{}", ident), ident.span());
+            if pattern.sign_bits()?.contains_key(&RawMappingValue::Name(ident.to_string())) {
+                declarations.push(quote! { let mut #new_ident = 0i128; });
+            } else {
+                declarations.push(quote! { let mut #new_ident = 0u128; });
+            }
+        }
+        (declarations, values)
+    };
+    let value = match fields {
+        Fields::Named(_) => quote! { #constructor { #(#values),* } },
+        Fields::Unnamed(_) => quote! { #constructor(#(#values),*) },
+        Fields::Unit => quote! { #constructor },
+    };
+    let reads = {
+        let mut reads = vec![];
+        let mut token_start = 0;
+        for token in pattern.tokens.iter().rev() {
+            match token {
+                Token::Bits(literal) | Token::Reserved(literal) => {
+                    token_start += literal.bits;
+                },
+                Token::DontCare(bits) => {
+                    token_start += bits;
+                },
+                Token::Range(range @ Range { start: range_start, len, .. }) => {
+                    let target_expr = range_expr(pattern, &mapping, range)?;
+                    reads.push(quote! {
+                        ::decree::BitSource::write(&input, &mut #target_expr, #token_start, #len, #range_start)?;
+                    });
+                    token_start += len;
+                },
+                Token::Error => return Err(syn::Error::new(pattern.span, "The pattern has a token that couldn't be read")),
+            }
+        }
+        for (value, bit) in pattern.sign_bits()? {
+            let target_expr = &mapping[&value];
+            reads.push(quote! {
+                ::decree::SignExtend::sign_extend(&mut #target_expr, #bit);
+            });
+        }
+        for (value, transforms) in &pattern.transforms {
+            if !referenced.contains(value) {
+                continue;
+            }
+            reads.push(decode_tokens(transforms, &mapping[value]));
+        }
+        let types = field_types(fields);
+        for (value, path) in &pattern.codecs {
+            if !referenced.contains(value) {
+                continue;
+            }
+            let target_expr = &mapping[value];
+            let ty = &types[value];
+            reads.push(quote! {
+                let #target_expr: #ty = #path::decode(#target_expr)?;
+            });
+        }
+        reads
+    };
+    let requirement = match pattern.requirement(&mapping, false) {
+        Some(requirement) => quote! {
+            if !(#requirement) {
+                return Ok(None);
+            }
+        },
+        None => quote! {},
+    };
+    let reserved = pattern.reserved_bits();
+    let reserved_check = if reserved.mask.iter().any(|byte| *byte != 0) {
+        let mask = byte_array_tokens(&reserved.mask);
+        let value = byte_array_tokens(&reserved.value);
+        quote! {
+            if !::decree::matches_pattern(&buffer, &#mask, &#value) {
+                return Err(::decree::Error::unpredictable(#target));
+            }
+        }
+    } else {
+        quote! {}
+    };
+    let bits = pattern.bits();
+    let body = quote! {
+        let input = ::decree::LittleEndian::<&[u8]>::with_bits(&buffer[..], #bits)?;
+        #(#declarations)*
+        #(#reads)*
+        #requirement
+        #reserved_check
+        Ok(Some(#value))
+    };
+    Ok(VariantDecoder {
+        name: None,
+        priority: pattern.priority.unwrap_or(0),
+        bits,
+        fixed_bits: pattern.fixed_bits(),
+        body,
+        checked: !pattern.requires.is_empty(),
+        constraints,
+    })
+}
@@ -0,0 +1,117 @@
+use proc_macro2::{TokenStream as TokenStreamInternal, Ident};
+use syn::{DeriveInput, spanned::Spanned, DataEnum};
+use quote::quote;
+
+use crate::{bitpattern::{BitPattern, Literal, Range, Token, find_bit_pattern}, common::{generics, generics_names, screaming_snake_case}};
+
+const GENERIC_FAILURE: &str = r#"#[derive(BitLayout)] expects an attribute of the form #[bitpattern("11010[a:0-2]0110", a=foo)]"#;
+
+pub fn expand_bit_layout(ast: &DeriveInput) -> syn::Result<TokenStreamInternal> {
+    let type_ident = &ast.ident;
+    let type_name = ast.ident.to_string();
+    let mut consts = vec![];
+    match &ast.data {
+        syn::Data::Struct(data_struct) => {
+            if let Some(pattern) = find_bit_pattern(&ast.attrs, &ast.attrs, &data_struct.fields)? {
+                let ident = Ident::new("LAYOUT", type_ident.span());
+                consts.push(layout_const(&ident, &format!("[`{}`]", type_name), &pattern));
+            } else {
+                return Err(syn::Error::new(ast.ident.span(), GENERIC_FAILURE))
+            }
+        },
+        syn::Data::Enum(DataEnum { variants, .. }) => {
+            for variant in variants {
+                if let Some(pattern) = find_bit_pattern(&ast.attrs, &variant.attrs, &variant.fields)? {
+                    let ident = Ident::new(&format!("LAYOUT_{}", screaming_snake_case(&variant.ident.to_string())), variant.ident.span());
+                    consts.push(layout_const(&ident, &format!("[`{}::{}`]", type_name, variant.ident), &pattern));
+                } else {
+                    return Err(syn::Error::new(variant.span(), GENERIC_FAILURE))
+                }
+            }
+        },
+        syn::Data::Union(_) => return Err(syn::Error::new(ast.span(), "#[derive(BitLayout)] is not supported for untagged unions.")),
+    };
+    let generics = generics(ast);
+    let generics_names = generics_names(ast);
+    let where_clause = &ast.generics.where_clause;
+    Ok(quote! {
+        impl #generics #type_ident #generics_names #where_clause {
+            #(#consts)*
+        }
+    })
+}
+
+/// A constant holding the layout table, documented with the same
+/// table. Derives can't add docs to the type or its variants, so this
+/// is the only place rustdoc shows the layout.
+fn layout_const(ident: &Ident, target: &str, pattern: &BitPattern) -> TokenStreamInternal {
+    let table = layout_table(pattern);
+    let doc = format!("The bit layout of {}.\n\n{}", target, table);
+    quote! {
+        #[doc = #doc]
+        pub const #ident: &'static str = #table;
+    }
+}
+
+/// A markdown table of the pattern, most significant bit first, with
+/// the bit positions of each token above what's stored there. Bits are
+/// numbered the way the pattern numbers them.
+fn layout_table(pattern: &BitPattern) -> String {
+    let msb0 = pattern.msb0 == Some(true);
+    let mut positions = vec![];
+    let mut contents = vec![];
+    let total = pattern.bits();
+    let mut end = total;
+    for token in &pattern.tokens {
+        let (bits, content) = match token {
+            Token::Bits(Literal { bytes, bits }) => (*bits, format!("`{}`", bit_string(bytes, *bits))),
+            Token::Reserved(Literal { bytes, bits }) => {
+                let reserved: String = bit_string(bytes, *bits).chars().map(|bit| format!("({})", bit)).collect();
+                (*bits, format!("`{}`", reserved))
+            },
+            Token::DontCare(bits) => (*bits, format!("`{}`", "x".repeat(*bits))),
+            Token::Range(Range { name, start, len, signed, .. }) => {
+                let field = pattern.resolve(name);
+                let range = if msb0 {
+                    let first = pattern.field_widths[&field] - (start + len);
+                    bit_range(first, first + len - 1)
+                } else {
+                    bit_range(start + len - 1, *start)
+                };
+                let sign = if *signed { " (signed)" } else { "" };
+                (*len, format!("{}[{}]{}", field, range, sign))
+            },
+            Token::Error => continue,
+        };
+        let position = if msb0 {
+            bit_range(total - end, total - end + bits - 1)
+        } else {
+            bit_range(end - 1, end - bits)
+        };
+        positions.push(position);
+        contents.push(content);
+        end -= bits;
+    }
+    let separators = vec!["---"; positions.len()];
+    format!(
+        "| {} |\n| {} |\n| {} |\n",
+        positions.join(" | "),
+        separators.join(" | "),
+        contents.join(" | "),
+    )
+}
+
+/// Bits `first` to `last`, written in the order given.
+fn bit_range(first: usize, last: usize) -> String {
+    if first == last {
+        format!("{}", first)
+    } else {
+        format!("{}:{}", first, last)
+    }
+}
+
+fn bit_string(bytes: &[u8], bits: usize) -> String {
+    (0..bits).rev()
+        .map(|bit| if (bytes[bit / 8] >> (bit % 8)) & 1 == 1 { '1' } else { '0' })
+        .collect()
+}
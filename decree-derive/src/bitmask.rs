@@ -0,0 +1,93 @@
+use proc_macro2::{TokenStream as TokenStreamInternal, Ident, Literal};
+use syn::{DeriveInput, spanned::Spanned, DataEnum};
+use quote::quote;
+
+use crate::{bitpattern::{BitPattern, find_bit_pattern}, common::{generics, generics_names, screaming_snake_case}};
+
+const GENERIC_FAILURE: &str = r#"#[derive(BitMask)] expects an attribute of the form #[bitpattern("11010[a:0-2]0110", a=foo)]"#;
+
+pub fn expand_bit_mask(ast: &DeriveInput) -> syn::Result<TokenStreamInternal> {
+    let type_ident = &ast.ident;
+    let patterns = match &ast.data {
+        syn::Data::Struct(data_struct) => {
+            if let Some(pattern) = find_bit_pattern(&ast.attrs, &ast.attrs, &data_struct.fields)? {
+                vec![(None, pattern)]
+            } else {
+                return Err(syn::Error::new(ast.ident.span(), GENERIC_FAILURE))
+            }
+        },
+        syn::Data::Enum(DataEnum { variants, .. }) => {
+            let mut patterns = vec![];
+            for variant in variants {
+                if let Some(pattern) = find_bit_pattern(&ast.attrs, &variant.attrs, &variant.fields)? {
+                    patterns.push((Some(&variant.ident), pattern));
+                } else {
+                    return Err(syn::Error::new(variant.span(), GENERIC_FAILURE))
+                }
+            }
+            patterns
+        },
+        syn::Data::Union(_) => return Err(syn::Error::new(ast.span(), "#[derive(BitMask)] is not supported for untagged unions.")),
+    };
+    let max_bits = patterns.iter().map(|(_, pattern)| pattern.bits()).max().unwrap_or(0);
+    let ty = match max_bits {
+        0..=8 => quote! { u8 },
+        9..=16 => quote! { u16 },
+        17..=32 => quote! { u32 },
+        33..=64 => quote! { u64 },
+        65..=128 => quote! { u128 },
+        _ => return Err(syn::Error::new(ast.ident.span(), format!("#[derive(BitMask)] supports patterns of up to 128 bits, but {} has {}.", type_ident, max_bits))),
+    };
+    let mut consts = vec![];
+    let mut entries = vec![];
+    for (ident, pattern) in &patterns {
+        let (mask, value) = mask_and_match(pattern);
+        let (mask_ident, match_ident, name) = match ident {
+            Some(ident) => {
+                let suffix = screaming_snake_case(&ident.to_string());
+                (
+                    Ident::new(&format!("MASK_{}", suffix), ident.span()),
+                    Ident::new(&format!("MATCH_{}", suffix), ident.span()),
+                    ident.to_string(),
+                )
+            },
+            None => (
+                Ident::new("MASK", type_ident.span()),
+                Ident::new("MATCH", type_ident.span()),
+                type_ident.to_string(),
+            ),
+        };
+        let mask_doc = format!("The fixed bits of the pattern for {}.", name);
+        let match_doc = format!("The values of the fixed bits of the pattern for {}.", name);
+        consts.push(quote! {
+            #[doc = #mask_doc]
+            pub const #mask_ident: #ty = #mask;
+            #[doc = #match_doc]
+            pub const #match_ident: #ty = #value;
+        });
+        entries.push(quote! { (#name, #mask, #value) });
+    }
+    let generics = generics(ast);
+    let generics_names = generics_names(ast);
+    let where_clause = &ast.generics.where_clause;
+    Ok(quote! {
+        impl #generics #type_ident #generics_names #where_clause {
+            #(#consts)*
+
+            /// The name, mask and match value of each pattern, in
+            /// declaration order.
+            pub const PATTERNS: &'static [(&'static str, #ty, #ty)] = &[#(#entries),*];
+        }
+    })
+}
+
+fn mask_and_match(pattern: &BitPattern) -> (Literal, Literal) {
+    let fixed_bits = pattern.fixed_bits();
+    let to_int = |bytes: &[u8]| bytes.iter()
+        .rev()
+        .fold(0u128, |value, byte| (value << 8) | *byte as u128);
+    (
+        Literal::u128_unsuffixed(to_int(&fixed_bits.mask)),
+        Literal::u128_unsuffixed(to_int(&fixed_bits.value)),
+    )
+}
@@ -1,12 +1,14 @@
-use std::collections::{HashSet, HashMap};
+use std::collections::{HashSet, HashMap, BTreeMap};
 
-use logos::{Logos, Lexer};
+use logos::Logos;
 
 use proc_macro::TokenStream as TokenStreamExternal;
-use proc_macro2::{Ident, TokenTree, Span};
-use syn::{spanned::Spanned, Attribute, Expr, Fields, Lit, ExprLit, ExprPath};
+use proc_macro2::{TokenStream as TokenStreamInternal, Ident, TokenTree, Span};
+use syn::{punctuated::Punctuated, spanned::Spanned, Attribute, Expr, Fields, Lit, ExprBinary, ExprLit, ExprParen, ExprPath, ExprUnary, LitStr, Path, UnOp};
 use quote::{quote, quote_spanned};
 
+use crate::{binding::bind_names, diagnostic::PatternSpan, fieldlayout::{declared_widths, field_layout_pattern, find_layout_options}, format::{find_formats, expand_format}, specfile::SpecEntry, transform::{Transform, split_transforms}};
+
 pub fn compute_mapping(fields: &Fields, pattern: &BitPattern) -> syn::Result<HashMap<RawMappingValue, Expr>> {
     let mut existing_fields = HashSet::new();
     let mut mapping = HashMap::new();
@@ -41,6 +43,9 @@ pub fn compute_mapping(fields: &Fields, pattern: &BitPattern) -> syn::Result<Has
     }
     for (new_name, field_name) in pattern.renames.iter() {
         let name = RawMappingValue::Name(new_name.to_string());
+        if name == RawMappingValue::from(field_name.clone()) {
+            continue;
+        }
         if !mapping.contains_key(&name) {
             mapping.insert(name, field_name.clone().into());
         } else {
@@ -49,16 +54,104 @@ pub fn compute_mapping(fields: &Fields, pattern: &BitPattern) -> syn::Result<Has
             return Err(syn::Error::new(new_name.span(), format!("\"{}\" is already mapped to \".{}\"", new_name, existing)));
         }
     }
+    for (ident, _) in &pattern.computed {
+        let name = RawMappingValue::Name(ident.to_string());
+        if mapping.contains_key(&name) {
+            return Err(syn::Error::new(ident.span(), format!("\"{}\" is already a field, or mapped onto one", ident)));
+        }
+        let expr: Expr = {
+            let ident = Ident::new_raw(&format!("____{}", ident), ident.span());
+            let tokens: TokenStreamExternal = quote! { #ident }.into();
+            syn::parse(tokens)?
+        };
+        mapping.insert(name, expr);
+    }
+    for token in &pattern.tokens {
+        if let Token::Range(range) = token {
+            let name = &range.name;
+            let (value, hint) = match name.parse::<usize>() {
+                Ok(index) => (RawMappingValue::Index(index), String::new()),
+                Err(_) => (RawMappingValue::Name(name.to_string()), format!(" Use {} = field to map it onto one.", name)),
+            };
+            if !mapping.contains_key(&value) {
+                return Err(pattern.range_error(range, &format!("The pattern has a range for \"{}\", which isn't a field.{}", name, hint)));
+            }
+        }
+    }
     Ok(mapping)
 }
 
-pub fn find_bit_pattern(attrs: &[Attribute]) -> syn::Result<Option<BitPattern>> {
+/// The expression `mapping` binds the field `range` refers to.
+pub fn range_expr<'a>(pattern: &BitPattern, mapping: &'a HashMap<RawMappingValue, Expr>, range: &Range) -> syn::Result<&'a Expr> {
+    let value = match range.name.parse::<usize>() {
+        Ok(index) => RawMappingValue::Index(index),
+        Err(_) => RawMappingValue::Name(range.name.to_string()),
+    };
+    mapping.get(&value)
+        .ok_or_else(|| pattern.range_error(range, &format!("The pattern has a range for \"{}\", which isn't a field.", range.name)))
+}
+
+/// Finds the `#[bitpattern]` in `attrs`. Formats it names are looked
+/// up in `type_attrs`, the attributes of the type being derived.
+pub fn find_bit_pattern(type_attrs: &[Attribute], attrs: &[Attribute], fields: &Fields) -> syn::Result<Option<BitPattern>> {
+    let mut pattern = match parse_bit_pattern_attr(type_attrs, attrs, fields)? {
+        Some(pattern) => pattern,
+        None => match field_layout_pattern(type_attrs, attrs, fields)? {
+            Some(pattern) => pattern,
+            None => return Ok(None),
+        },
+    };
+    let FieldOptions { codecs, skipped } = field_options(fields)?;
+    pattern.codecs = codecs;
+    pattern.skipped = skipped;
+    for (field, path) in &pattern.codecs {
+        if pattern.transforms.contains_key(field) {
+            return Err(syn::Error::new(path.span(), format!("\"{}\" can't have both a transform and a with codec", field)));
+        }
+    }
+    check_unreferenced(fields, &pattern)?;
+    Ok(Some(pattern))
+}
+
+/// Makes sure every field is either in the pattern or marked with
+/// `#[bitpattern(skip)]`, so encoding doesn't quietly drop it and
+/// decoding doesn't quietly leave it as its default.
+fn check_unreferenced(fields: &Fields, pattern: &BitPattern) -> syn::Result<()> {
+    let referenced = pattern.referenced_values();
+    for (index, field) in fields.iter().enumerate() {
+        let name = match &field.ident {
+            Some(ident) => RawMappingValue::Name(ident.to_string()),
+            None => RawMappingValue::Index(index),
+        };
+        match (referenced.contains(&name), pattern.skipped.contains_key(&name)) {
+            (true, true) => return Err(syn::Error::new(field.span(), format!("\"{}\" is marked #[bitpattern(skip)], but the pattern has a range for it", name))),
+            (false, false) => return Err(syn::Error::new(field.span(), format!("\"{}\" isn't in the pattern, so it wouldn't be encoded or decoded. Add a range for it, or mark it #[bitpattern(skip)] to leave it as its default.", name))),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+fn parse_bit_pattern_attr(type_attrs: &[Attribute], attrs: &[Attribute], fields: &Fields) -> syn::Result<Option<BitPattern>> {
     for attr in attrs {
         if let Some(ident) = attr.path.get_ident() {
             if ident.to_string() == "bitpattern" {
                 if let Some(TokenTree::Group(group)) = attr.tokens.clone().into_iter().next() {
                     let tokens: TokenStreamExternal = group.stream().into();
-                    let pattern: BitPattern = syn::parse(tokens)?;
+                    let mut pattern: BitPattern = syn::parse(tokens)?;
+                    if let Some(format) = &pattern.format {
+                        let expanded = expand_format(&find_formats(type_attrs)?, format, &pattern.slots)?;
+                        pattern.tokens = expanded.tokens;
+                        pattern.text = expanded.text;
+                        pattern.source = expanded.source;
+                    }
+                    if let Some(spec) = &pattern.spec {
+                        pattern.tokens = spec.tokens()?;
+                    }
+                    if pattern.msb0.or(find_layout_options(type_attrs)?.msb0) == Some(true) {
+                        pattern.renumber_msb0(&declared_widths(fields)?)?;
+                    }
+                    pattern.fold_constants()?;
                     return Ok(Some(pattern));
                 }
             }
@@ -67,11 +160,85 @@ pub fn find_bit_pattern(attrs: &[Attribute]) -> syn::Result<Option<BitPattern>>
     Ok(None)
 }
 
+/// An option given to a field with `#[bitpattern(...)]`.
+enum FieldOption {
+    With(Path),
+    Skip,
+    Default(Expr),
+}
+
+impl syn::parse::Parse for FieldOption {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let ident: Ident = input.parse()?;
+        if ident == "skip" {
+            return Ok(FieldOption::Skip);
+        }
+        let _eq: syn::Token![=] = input.parse()?;
+        if ident == "with" {
+            Ok(FieldOption::With(input.parse()?))
+        } else if ident == "default" {
+            Ok(FieldOption::Default(input.parse()?))
+        } else {
+            Err(syn::Error::new(ident.span(), "Expected a field attribute of the form #[bitpattern(with = path)], #[bitpattern(skip)] or #[bitpattern(skip, default = expr)]"))
+        }
+    }
+}
+
+/// The options given to a type's fields with `#[bitpattern(...)]`.
+struct FieldOptions {
+    /// The codecs given with `#[bitpattern(with = path)]`.
+    codecs: BTreeMap<RawMappingValue, Path>,
+    /// The fields left out with `#[bitpattern(skip)]`, and the values
+    /// they're given when decoding, if not their defaults.
+    skipped: BTreeMap<RawMappingValue, Option<Expr>>,
+}
+
+fn field_options(fields: &Fields) -> syn::Result<FieldOptions> {
+    let mut codecs = BTreeMap::new();
+    let mut skipped = BTreeMap::new();
+    for (index, field) in fields.iter().enumerate() {
+        let name = match &field.ident {
+            Some(ident) => RawMappingValue::Name(ident.to_string()),
+            None => RawMappingValue::Index(index),
+        };
+        for attr in &field.attrs {
+            if attr.path.is_ident("bitpattern") {
+                let options = attr.parse_args_with(Punctuated::<FieldOption, syn::Token![,]>::parse_terminated)?;
+                let mut skip = false;
+                let mut default = None;
+                for option in options {
+                    match option {
+                        FieldOption::With(path) => {
+                            codecs.insert(name.clone(), path);
+                        },
+                        FieldOption::Skip => skip = true,
+                        FieldOption::Default(expr) => default = Some(expr),
+                    }
+                }
+                match (skip, default) {
+                    (true, default) => {
+                        skipped.insert(name.clone(), default);
+                    },
+                    (false, Some(default)) => return Err(syn::Error::new(default.span(), "A default is only used for fields marked #[bitpattern(skip)]")),
+                    (false, None) => {},
+                }
+            }
+        }
+    }
+    Ok(FieldOptions { codecs, skipped })
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Range {
     pub name: String,
     pub start: usize,
     pub len: usize,
+    /// The last bit of this range is the field's sign bit, so it's
+    /// copied into every bit above it on decode.
+    pub signed: bool,
+    /// The bytes of the pattern the range was read from, if it was
+    /// written in one.
+    pub offsets: Option<std::ops::Range<usize>>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -80,19 +247,68 @@ pub struct Literal {
     pub bits: usize,
 }
 
+/// The fixed bits of a pattern, as little endian bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixedBits {
+    pub mask: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl FixedBits {
+    /// Returns true if some input can match both `self` and `other`.
+    pub fn overlaps(&self, other: &FixedBits) -> bool {
+        self.mask.iter()
+            .zip(self.value.iter())
+            .zip(other.mask.iter().zip(other.value.iter()))
+            .all(|((mask, value), (other_mask, other_value))| mask & other_mask & (value ^ other_value) == 0)
+    }
+
+    /// Returns the value this pattern requires for `bit`, or None if
+    /// the bit isn't fixed.
+    pub fn get(&self, bit: usize) -> Option<bool> {
+        let byte = bit / 8;
+        if byte >= self.mask.len() || (self.mask[byte] >> (bit % 8)) & 1 == 0 {
+            None
+        } else {
+            Some((self.value[byte] >> (bit % 8)) & 1 == 1)
+        }
+    }
+
+    pub fn bits(&self) -> usize {
+        self.mask.len() * 8
+    }
+}
+
 #[derive(Logos, Debug, PartialEq, Eq)]
 pub enum Token {
     #[regex("[01]+", |lex| parse_literal(lex.slice()))]
+    #[regex(r"\d+'[bBoOdDhH][0-9a-fA-F]+", lex_sized_literal)]
     Bits(Literal),
 
-    #[regex(r#"\[([a-zA-Z0-9_]+):\d+(-\d+)?\]"#, parse_range)]
+    #[regex(r#"\[([a-zA-Z0-9_]+):\d+(-\d+)?(:s)?\]"#, |lex| parse_range(lex.slice()).ok())]
     Range(Range),
 
+    /// Bits written as `(0)` or `(1)` that should have that value.
+    /// They're written like literal bits on encode, but don't affect
+    /// which pattern matches on decode. A matching input where they
+    /// have the wrong value decodes as `Error::Unpredictable`.
+    #[regex(r"(\([01]\))+", |lex| parse_literal(&lex.slice().replace(['(', ')'], "")))]
+    Reserved(Literal),
+
+    /// Bits that are ignored on decode, and written as the pattern's
+    /// `dont_care` value on encode.
+    #[regex("[x?]+", |lex| lex.slice().len())]
+    DontCare(usize),
+
+    /// Whitespace, `_` and `|` separators, and `//` comments are
+    /// skipped.
     #[error]
+    #[regex(r"[ \t\r\n_|]+", logos::skip)]
+    #[regex(r"//[^\n]*", logos::skip)]
     Error,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash)]
 pub enum RawMappingValue {
     Index(usize),
     Name(String),
@@ -157,9 +373,8 @@ impl MappingValue {
     }
 }
 
-impl syn::parse::Parse for MappingValue {
-    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        let expr: Expr = input.parse()?;
+impl MappingValue {
+    pub fn from_expr(expr: Expr) -> syn::Result<Self> {
         let span = expr.span();
         match expr {
             Expr::Lit(ExprLit { lit, .. }) => {
@@ -181,124 +396,575 @@ impl syn::parse::Parse for MappingValue {
     }
 }
 
+impl syn::parse::Parse for MappingValue {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        MappingValue::from_expr(input.parse()?)
+    }
+}
+
+/// What a name used in a pattern is mapped onto with `name = ...`.
+enum Mapping {
+    /// A field, and the transforms applied to it.
+    Field(MappingValue, Vec<Transform>),
+    /// An integer literal with a type suffix, like `4u8`.
+    Constant(Span, i128),
+    /// Any other expression, computed from the fields when encoding.
+    /// Decoding skips its bits without checking them.
+    Computed(Expr),
+}
+
+/// Whether `expr` is a field followed by operators with integer
+/// literals, like `offset * 2`. These are meant as transforms, so if
+/// an operator isn't one, that's an error rather than a computed
+/// mapping.
+fn is_transform_like(expr: &Expr) -> bool {
+    match expr {
+        Expr::Paren(ExprParen { expr, .. }) => is_transform_like(expr),
+        Expr::Binary(ExprBinary { left, right, .. }) => {
+            matches!(&**right, Expr::Lit(ExprLit { lit: Lit::Int(_), .. })) && is_transform_like(left)
+        },
+        expr => MappingValue::from_expr(expr.clone()).is_ok(),
+    }
+}
+
+/// The value of a constant mapping like `4u8` or `-1i8`. Integers
+/// without a suffix are field indexes, so they aren't constants.
+fn constant_value(expr: &Expr) -> syn::Result<Option<i128>> {
+    match expr {
+        Expr::Lit(ExprLit { lit: Lit::Int(lit_int), .. }) if !lit_int.suffix().is_empty() => Ok(Some(lit_int.base10_parse()?)),
+        Expr::Unary(ExprUnary { op: UnOp::Neg(_), expr, .. }) => Ok(constant_value(expr)?.map(|value| -value)),
+        _ => Ok(None),
+    }
+}
+
 #[derive(Debug)]
 pub struct BitPattern {
     pub tokens: Vec<Token>,
     pub renames: Vec<(Ident, MappingValue)>,
+    /// Decoding order for patterns that overlap; higher goes first.
+    pub priority: Option<i64>,
+    /// The value written for don't care bits.
+    pub dont_care: bool,
+    /// Predicates on the fields that must hold for the pattern to
+    /// decode or encode.
+    pub requires: Vec<Expr>,
+    /// The transforms applied to each field's value to get the bits
+    /// stored for it.
+    pub transforms: BTreeMap<RawMappingValue, Vec<Transform>>,
+    /// The modules given with `#[bitpattern(with = path)]` that
+    /// convert fields to and from the bits stored for them.
+    pub codecs: BTreeMap<RawMappingValue, Path>,
+    /// The fields marked `#[bitpattern(skip)]`, which aren't encoded,
+    /// and the expressions they're decoded as instead of their
+    /// defaults.
+    pub skipped: BTreeMap<RawMappingValue, Option<Expr>>,
+    /// Names mapped onto a constant, like `version = 4u8`. Their
+    /// ranges are turned into literal bits.
+    pub constants: Vec<(Ident, Span, i128)>,
+    /// Names mapped onto an expression, like `len = items.len() as u8`,
+    /// that's computed from the fields when encoding. Decoding reads
+    /// their bits and throws them away without checking them.
+    pub computed: Vec<(Ident, Expr)>,
+    /// The format named with `format = Name`, if the pattern is
+    /// filled in from one, and the values for its slots.
+    pub format: Option<Ident>,
+    pub slots: Vec<(Ident, LitStr)>,
+    /// The opcodes file the pattern is read from with `file = "..."`,
+    /// and the entry in it.
+    pub spec: Option<SpecEntry>,
+    /// Whether bits are numbered from the most significant one, as
+    /// given with `numbering = msb0` or `lsb0`.
+    pub msb0: Option<bool>,
+    /// The width of each field when bits are numbered from the most
+    /// significant one, which the range indices count down from.
+    pub field_widths: BTreeMap<RawMappingValue, usize>,
+    pub span: Span,
+    /// The pattern the tokens were read from, and where it was
+    /// written, so errors can point at the part that's wrong.
+    pub text: String,
+    pub source: PatternSpan,
 }
 
 impl BitPattern {
+    /// A pattern of `tokens` read from `text`, which was written at
+    /// `source`, with none of the options set.
+    pub fn new(tokens: Vec<Token>, text: &str, source: &PatternSpan) -> Self {
+        BitPattern {
+            tokens,
+            renames: vec![],
+            priority: None,
+            dont_care: false,
+            requires: vec![],
+            transforms: BTreeMap::new(),
+            codecs: BTreeMap::new(),
+            skipped: BTreeMap::new(),
+            constants: vec![],
+            computed: vec![],
+            format: None,
+            slots: vec![],
+            spec: None,
+            msb0: None,
+            field_widths: BTreeMap::new(),
+            span: source.span(),
+            text: text.to_string(),
+            source: source.clone(),
+        }
+    }
+
     pub fn bits(&self) -> usize {
-        let mut bits = 0;
+        let mut bits: usize = 0;
         for token in &self.tokens {
+            let len = match token {
+                Token::Bits(literal) | Token::Reserved(literal) => literal.bits,
+                Token::Range(range) => range.len,
+                Token::DontCare(len) => *len,
+                Token::Error => 0,
+            };
+            bits = bits.saturating_add(len);
+        }
+        bits
+    }
+
+    /// The literal bits in this pattern, and the values they must
+    /// have.
+    pub fn fixed_bits(&self) -> FixedBits {
+        self.literal_bits(false)
+    }
+
+    /// The reserved bits in this pattern, and the values they should
+    /// have.
+    pub fn reserved_bits(&self) -> FixedBits {
+        self.literal_bits(true)
+    }
+
+    fn literal_bits(&self, reserved: bool) -> FixedBits {
+        let num_bytes = (self.bits() + 7) / 8;
+        let mut mask = vec![0u8; num_bytes];
+        let mut value = vec![0u8; num_bytes];
+        let mut token_start = 0;
+        for token in self.tokens.iter().rev() {
             match token {
-                Token::Bits(literal) => bits += literal.bits,
-                Token::Range(range) => bits += range.len,
+                Token::Bits(Literal { bytes, bits }) | Token::Reserved(Literal { bytes, bits }) => {
+                    if matches!(token, Token::Reserved(_)) == reserved {
+                        for bit in 0..*bits {
+                            let pos = token_start + bit;
+                            mask[pos / 8] |= 1 << (pos % 8);
+                            if (bytes[bit / 8] >> (bit % 8)) & 1 == 1 {
+                                value[pos / 8] |= 1 << (pos % 8);
+                            }
+                        }
+                    }
+                    token_start += bits;
+                },
+                Token::Range(range) => token_start += range.len,
+                Token::DontCare(len) => token_start += len,
                 Token::Error => {},
             }
         }
-        bits
+        FixedBits { mask, value }
     }
 
     pub fn referenced_values(&self) -> HashSet<RawMappingValue> {
         let mut values = HashSet::new();
-        let renames = {
-            let mut renames = HashMap::new();
-            for (ident, value) in &self.renames {
-                renames.insert(ident.to_string(), value.clone());
-            }
-            renames
-        };
         for token in &self.tokens {
             if let Token::Range(Range { name, .. }) = token {
-                if let Ok(index) = name.parse::<usize>() {
-                    values.insert(RawMappingValue::Index(index));
-                } else {
-                    if let Some(mapping_value) = renames.get(name) {
-                        let mapping_value: RawMappingValue = mapping_value.clone().into();
-                        values.insert(mapping_value);
-                    } else {
-                        values.insert(RawMappingValue::Name(name.to_string()));
+                values.insert(self.resolve(name));
+            }
+        }
+        values
+    }
+
+    /// The number of bits of each field that the ranges use, up to
+    /// and including the highest one.
+    pub fn used_bits(&self) -> BTreeMap<RawMappingValue, usize> {
+        let mut used = BTreeMap::new();
+        for token in &self.tokens {
+            if let Token::Range(Range { name, start, len, .. }) = token {
+                let bits = used.entry(self.resolve(name)).or_insert(0);
+                *bits = usize::max(*bits, start + len);
+            }
+        }
+        used
+    }
+
+    /// The field a range name refers to, after applying renames.
+    pub fn resolve(&self, name: &str) -> RawMappingValue {
+        resolve_name(&self.renames, name)
+    }
+
+    /// The sign bit of each field that has one marked with `:s`.
+    pub fn sign_bits(&self) -> syn::Result<BTreeMap<RawMappingValue, usize>> {
+        let mut signed_ranges = BTreeMap::new();
+        let mut highest_bits = HashMap::new();
+        for token in &self.tokens {
+            if let Token::Range(range) = token {
+                let value = self.resolve(&range.name);
+                let end = range.start + range.len - 1;
+                let highest = highest_bits.entry(value.clone()).or_insert(end);
+                *highest = usize::max(*highest, end);
+                if range.signed && signed_ranges.insert(value.clone(), range).is_some() {
+                    return Err(self.range_error(range, &format!("Only one range for \"{}\" can be marked as signed.", value)));
+                }
+            }
+        }
+        let mut sign_bits = BTreeMap::new();
+        for (value, range) in signed_ranges {
+            let bit = range.start + range.len - 1;
+            let highest = highest_bits[&value];
+            if highest != bit {
+                return Err(self.range_error(range, &format!("The signed range for \"{}\" ends at bit {}, but bit {} of it is also used. Only the range with the highest bit can be signed.", value, bit, highest)));
+            }
+            sign_bits.insert(value, bit);
+        }
+        Ok(sign_bits)
+    }
+
+    /// An error about `range`, pointing at where it was written in the
+    /// pattern, or at the whole pattern if it wasn't written in one.
+    pub fn range_error(&self, range: &Range, message: &str) -> syn::Error {
+        match &range.offsets {
+            Some(offsets) => self.source.error(&self.text, offsets.clone(), message),
+            None => syn::Error::new(self.span, message),
+        }
+    }
+
+    /// Converts ranges written with bit 0 as the most significant bit
+    /// of each field to ones counting from the least significant bit.
+    /// A field is as wide as `declared` says, or as the highest index
+    /// any of its ranges use if it isn't there.
+    fn renumber_msb0(&mut self, declared: &BTreeMap<RawMappingValue, (Span, usize)>) -> syn::Result<()> {
+        let mut widths = self.used_bits();
+        for (value, width) in widths.iter_mut() {
+            if let Some((span, declared)) = declared.get(value) {
+                if declared < width {
+                    return Err(syn::Error::new(*span, format!("\"{}\" is {} bits wide, but the pattern uses bit {} of it", value, declared, *width - 1)));
+                }
+                *width = *declared;
+            }
+        }
+        for token in self.tokens.iter_mut() {
+            if let Token::Range(Range { name, start, len, .. }) = token {
+                let width = widths[&resolve_name(&self.renames, name)];
+                *start = width - (*start + *len);
+            }
+        }
+        self.msb0 = Some(true);
+        self.field_widths = widths;
+        Ok(())
+    }
+
+    /// The `require` predicates as a single expression, with the names
+    /// in them bound through `mapping`. Set `by_ref` if the bindings
+    /// are references to the fields.
+    pub fn requirement(&self, mapping: &HashMap<RawMappingValue, Expr>, by_ref: bool) -> Option<TokenStreamInternal> {
+        let bindings = self.bindings(mapping, by_ref);
+        let mut predicates = self.requires.iter()
+            .map(|expr| bind_names(expr, &bindings));
+        let first = predicates.next()?;
+        Some(predicates.fold(quote! { (#first) }, |tokens, predicate| quote! { #tokens && (#predicate) }))
+    }
+
+    /// The names of the fields, bound to the expressions in `mapping`.
+    fn bindings(&self, mapping: &HashMap<RawMappingValue, Expr>, by_ref: bool) -> HashMap<String, TokenStreamInternal> {
+        let mut bindings = HashMap::new();
+        for (value, expr) in mapping {
+            if let RawMappingValue::Name(name) = value {
+                if self.computed.iter().any(|(ident, _)| ident == name) {
+                    continue;
+                }
+                let tokens = if by_ref { quote! { (*#expr) } } else { quote! { #expr } };
+                bindings.insert(name.clone(), tokens);
+            }
+        }
+        bindings
+    }
+
+    /// The expression computing the value for `name` when encoding,
+    /// if it's mapped onto one, with the fields bound by reference
+    /// through `mapping` and `self` bound to `source`.
+    pub fn computed_value(&self, name: &str, mapping: &HashMap<RawMappingValue, Expr>) -> Option<TokenStreamInternal> {
+        let (_, expr) = self.computed.iter().find(|(ident, _)| ident == name)?;
+        let mut bindings = self.bindings(mapping, true);
+        bindings.insert("self".to_string(), quote! { source });
+        Some(bind_names(expr, &bindings))
+    }
+
+    pub fn is_computed(&self, name: &str) -> bool {
+        self.computed.iter().any(|(ident, _)| ident == name)
+    }
+
+    /// Replaces the ranges of names mapped onto constants with the
+    /// literal bits of the constant.
+    fn fold_constants(&mut self) -> syn::Result<()> {
+        let used = self.used_bits();
+        for (ident, span, value) in &self.constants {
+            let bits = match used.get(&RawMappingValue::Name(ident.to_string())) {
+                Some(bits) => *bits,
+                None => continue,
+            };
+            let fits = bits >= 128 || if *value < 0 { *value >= -(1i128 << (bits - 1)) } else { *value >> bits == 0 };
+            if !fits {
+                return Err(syn::Error::new(*span, format!("{} doesn't fit in the {} bits the pattern has for \"{}\"", value, bits, ident)));
+            }
+        }
+        for token in self.tokens.iter_mut() {
+            if let Token::Range(Range { name, start, len, .. }) = token {
+                if let Some((_, _, value)) = self.constants.iter().find(|(ident, ..)| ident == name) {
+                    let mut bytes = vec![0u8; (*len + 7) / 8];
+                    for bit in 0..*len {
+                        let shift = usize::min(*start + bit, 127);
+                        if (value >> shift) & 1 == 1 {
+                            bytes[bit / 8] |= 1 << (bit % 8);
+                        }
                     }
+                    *token = Token::Bits(Literal { bytes, bits: *len });
                 }
             }
         }
-        values
+        Ok(())
+    }
+
+    /// The `require` predicates as written, for error messages.
+    pub fn requirement_string(&self) -> String {
+        self.requires.iter()
+            .map(|expr| quote! { #expr }.to_string())
+            .collect::<Vec<String>>()
+            .join(" && ")
     }
 }
 
+fn resolve_name(renames: &[(Ident, MappingValue)], name: &str) -> RawMappingValue {
+    if let Ok(index) = name.parse::<usize>() {
+        return RawMappingValue::Index(index);
+    }
+    for (ident, value) in renames {
+        if ident == name {
+            return value.clone().into();
+        }
+    }
+    RawMappingValue::Name(name.to_string())
+}
+
 impl syn::parse::Parse for BitPattern {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        fn parse_mapping(input: &mut syn::parse::ParseStream) -> syn::Result<(Ident, MappingValue)> {
+        fn parse_mapping(input: &mut syn::parse::ParseStream) -> syn::Result<(Ident, Mapping)> {
             let ident: Ident = input.parse()?;
             let _eq: syn::Token![=] = input.parse()?;
-            let value: MappingValue = input.parse()?;
-            Ok((ident, value))
+            let expr: Expr = input.parse()?;
+            if let Some(value) = constant_value(&expr)? {
+                return Ok((ident, Mapping::Constant(expr.span(), value)));
+            }
+            match split_transforms(expr.clone()) {
+                Ok((inner, transforms)) => {
+                    if let Ok(value) = MappingValue::from_expr(inner) {
+                        return Ok((ident, Mapping::Field(value, transforms)));
+                    }
+                },
+                Err(error) if is_transform_like(&expr) => return Err(error),
+                Err(_) => {},
+            }
+            Ok((ident, Mapping::Computed(expr)))
+        }
+        fn is_slot(input: syn::parse::ParseStream) -> bool {
+            let fork = input.fork();
+            fork.parse::<Ident>().is_ok() && fork.parse::<syn::Token![=]>().is_ok() && fork.peek(LitStr)
         }
         let mut input = input;
-        let expr: Expr = input.parse()?;
-        let pattern = string_lit(&expr, "a bit pattern")?;
-        let mut pattern = parse_bit_pattern(expr.span(), &pattern)?;
+        let mut pattern = if input.peek(syn::Ident) && input.peek2(syn::Token![=]) && input.fork().parse::<Ident>()? == "format" {
+            let _ident: Ident = input.parse()?;
+            let _eq: syn::Token![=] = input.parse()?;
+            let format: Ident = input.parse()?;
+            let mut pattern = BitPattern::new(vec![], "", &PatternSpan::new(format.span()));
+            pattern.format = Some(format);
+            pattern
+        } else if input.peek(syn::Ident) && input.peek2(syn::Token![=]) && input.fork().parse::<Ident>()? == "file" {
+            let _ident: Ident = input.parse()?;
+            let _eq: syn::Token![=] = input.parse()?;
+            let file: LitStr = input.parse()?;
+            let mut pattern = BitPattern::new(vec![], "", &PatternSpan::new(file.span()));
+            pattern.spec = Some(SpecEntry { file, entry: None, args: None });
+            pattern
+        } else {
+            let expr: Expr = input.parse()?;
+            let pattern = string_lit(&expr, "a bit pattern")?;
+            parse_bit_pattern(&PatternSpan::literal(&pattern), &pattern.value())?
+        };
         while !input.is_empty() {
             let _comma: syn::Token![,] = input.parse()?;
-            pattern.renames.push(parse_mapping(&mut input)?);
+            let option = if input.peek(syn::Ident) && input.peek2(syn::Token![=]) {
+                Some(input.fork().parse::<Ident>()?.to_string())
+            } else {
+                None
+            };
+            if let (Some("entry" | "args"), Some(spec)) = (option.as_deref(), &mut pattern.spec) {
+                let ident: Ident = input.parse()?;
+                let _eq: syn::Token![=] = input.parse()?;
+                let value: LitStr = input.parse()?;
+                if ident == "entry" {
+                    spec.entry = Some(value);
+                } else {
+                    spec.args = Some(value);
+                }
+            } else if option.is_some() && is_slot(input) {
+                let ident: Ident = input.parse()?;
+                let _eq: syn::Token![=] = input.parse()?;
+                let value: LitStr = input.parse()?;
+                if pattern.format.is_none() {
+                    return Err(syn::Error::new(ident.span(), format!("\"{}\" is given a pattern, but slots can only be filled in with format = Name", ident)));
+                }
+                pattern.slots.push((ident, value));
+            } else if option.as_deref() == Some("priority") {
+                let _ident: Ident = input.parse()?;
+                let _eq: syn::Token![=] = input.parse()?;
+                let negative = input.parse::<Option<syn::Token![-]>>()?.is_some();
+                let priority: i64 = input.parse::<syn::LitInt>()?.base10_parse()?;
+                pattern.priority = Some(if negative { -priority } else { priority });
+            } else if option.as_deref() == Some("dont_care") {
+                let _ident: Ident = input.parse()?;
+                let _eq: syn::Token![=] = input.parse()?;
+                let value: syn::LitInt = input.parse()?;
+                pattern.dont_care = match value.base10_parse::<u8>()? {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(syn::Error::new(value.span(), "Expected dont_care to be 0 or 1")),
+                };
+            } else if option.as_deref() == Some("numbering") {
+                let _ident: Ident = input.parse()?;
+                let _eq: syn::Token![=] = input.parse()?;
+                let value: Ident = input.parse()?;
+                pattern.msb0 = match value.to_string().as_str() {
+                    "msb0" => Some(true),
+                    "lsb0" => Some(false),
+                    _ => return Err(syn::Error::new(value.span(), "Expected numbering to be msb0 or lsb0")),
+                };
+            } else if option.as_deref() == Some("require") {
+                let _ident: Ident = input.parse()?;
+                let _eq: syn::Token![=] = input.parse()?;
+                pattern.requires.push(input.parse()?);
+            } else {
+                let (ident, value, transforms) = match parse_mapping(&mut input)? {
+                    (ident, Mapping::Field(value, transforms)) => (ident, value, transforms),
+                    (ident, Mapping::Constant(span, value)) => {
+                        pattern.constants.push((ident, span, value));
+                        continue;
+                    },
+                    (ident, Mapping::Computed(expr)) => {
+                        pattern.computed.push((ident, expr));
+                        continue;
+                    },
+                };
+                if !transforms.is_empty() {
+                    let field = RawMappingValue::from(value.clone());
+                    match pattern.transforms.get(&field) {
+                        Some(existing) if *existing != transforms => {
+                            return Err(syn::Error::new(ident.span(), format!("\"{}\" is already mapped with different transforms", value)));
+                        },
+                        _ => {
+                            pattern.transforms.insert(field, transforms);
+                        },
+                    }
+                }
+                pattern.renames.push((ident, value));
+            }
         }
         Ok(pattern)
     }
 }
 
-pub fn string_lit(lit: &Expr, kind: &str) -> syn::Result<String> {
+pub fn string_lit(lit: &Expr, kind: &str) -> syn::Result<LitStr> {
     match lit {
-        Expr::Lit(ExprLit { lit: Lit::Str(string), ..}) => Ok(string.value()),
+        Expr::Lit(ExprLit { lit: Lit::Str(string), ..}) => Ok(string.clone()),
         _ => Err(syn::Error::new(lit.span(), format!("Expected a string literal expressing {}.", kind))),
     }
 }
 
-pub fn parse_bit_pattern(span: Span, pattern: &str) -> syn::Result<BitPattern> {
+pub fn parse_bit_pattern(source: &PatternSpan, pattern: &str) -> syn::Result<BitPattern> {
     let mut lexer = Token::lexer(pattern);
     let mut results = vec![];
     while let Some(token) = lexer.next() {
-        if token == Token::Error {
-            let tspan = lexer.span();
-            let start = tspan.start;
-            let end = tspan.end;
-            return Err(syn::Error::new(span, &format!("Unexpected token at {}-{}: {}", tspan.start, tspan.end, &pattern[start..end])));
+        match token {
+            Token::Error => return Err(token_error(source, pattern, lexer.span().start)),
+            Token::Range(range) => results.push(Token::Range(Range { offsets: Some(lexer.span()), ..range })),
+            token => results.push(token),
         }
-        results.push(token);
     }
-    Ok(BitPattern { tokens: results, renames: vec![] })
+    Ok(BitPattern::new(results, pattern, source))
 }
 
-pub fn parse_range(lexer: &mut Lexer<Token>) -> Range {
-    let slice: &str = lexer.slice();
-    let mut colon = None;
-    let mut hyphen: Option<usize> = None;
-    let mut index = 0;
-    for chr in slice.chars() {
-        if chr == ':' {
-            colon = Some(index);
-        }
-        if colon.is_some() && chr == '-' {
-            hyphen = Some(index);
-        }
-        index += 1;
+/// Explains why the lexer couldn't read a token at `start`, pointing
+/// at the text that's wrong.
+fn token_error(source: &PatternSpan, pattern: &str, start: usize) -> syn::Error {
+    let rest = &pattern[start..];
+    let found = rest.chars().next().map_or(0, char::len_utf8);
+    if rest.starts_with('[') {
+        return match rest.find(']') {
+            Some(close) => {
+                let message = parse_range(&rest[..=close]).err().unwrap_or_else(|| "Expected a range like [name:a-b]".to_string());
+                source.error(pattern, start..(start + close + 1), &message)
+            },
+            None => source.error(pattern, start..pattern.len(), "Expected a ] to close the range"),
+        };
     }
-    let colon = colon.unwrap();
-    if let Some(hyphen) = hyphen {
-        let start: usize = slice[(colon + 1)..hyphen].parse().unwrap();
-        let end: usize = slice[(hyphen + 1)..(slice.len() - 1)].parse().unwrap();
-        Range {
-            name: slice[1..colon].to_string(),
-            start,
-            len: end + 1 - start,
-        }
-    } else {
-        let start: usize = slice[(colon + 1)..(slice.len() - 1)].parse().unwrap();
-        Range {
-            name: slice[1..colon].to_string(),
-            start,
-            len: 1,
-        }
+    if rest.starts_with('(') {
+        let end = rest.find(')').map_or(found, |close| close + 1);
+        return source.error(pattern, start..(start + end), "Reserved bits are written (0) or (1)");
+    }
+    let word = rest.find(|chr: char| !chr.is_ascii_alphanumeric() && chr != '\'' && chr != '_').unwrap_or(rest.len());
+    if rest[..word].contains('\'') {
+        let message = parse_sized_literal(&rest[..word]).err().unwrap_or_else(|| "Expected a literal like 7'h33".to_string());
+        return source.error(pattern, start..(start + word), &message);
+    }
+    source.error(pattern, start..(start + found), "Expected bits (0 or 1), a range like [name:a-b], reserved bits like (0), or don't care bits (x or ?)")
+}
+
+/// Parses a range like `[name:a-b]`, `[name:a]` or `[name:a-b:s]`,
+/// or says what's wrong with it.
+pub fn parse_range(slice: &str) -> Result<Range, String> {
+    let inner = match slice.strip_prefix('[').and_then(|inner| inner.strip_suffix(']')) {
+        Some(inner) => inner,
+        None => return Err("Expected a range like [name:a-b]".to_string()),
+    };
+    let (inner, signed) = match inner.strip_suffix(":s") {
+        Some(inner) => (inner, true),
+        None => (inner, false),
+    };
+    let (name, bits) = match inner.split_once(':') {
+        Some(parts) => parts,
+        None => return Err("Expected a range like [name:a-b], with a colon after the field's name".to_string()),
+    };
+    if name.is_empty() || !name.chars().all(|chr| chr.is_ascii_alphanumeric() || chr == '_') {
+        return Err(format!("Expected a field name or index before the colon, but found \"{}\"", name));
+    }
+    let (start, end) = match bits.split_once('-') {
+        Some((start, end)) => (parse_index(start)?, parse_index(end)?),
+        None => {
+            let index = parse_index(bits)?;
+            (index, index)
+        },
+    };
+    if end < start {
+        return Err(format!("The range end {} precedes its start {}. Ranges are written low bit first, as in [{}:{}-{}]", end, start, name, end, start));
+    }
+    Ok(Range {
+        name: name.to_string(),
+        start,
+        len: end + 1 - start,
+        signed,
+        offsets: None,
+    })
+}
+
+/// The highest bit index a range can use, since no field is wider
+/// than a `u128`.
+const MAX_INDEX: usize = 127;
+
+fn parse_index(index: &str) -> Result<usize, String> {
+    if index.is_empty() || !index.chars().all(|chr| chr.is_ascii_digit()) {
+        return Err(format!("Expected a bit index, but found \"{}\"", index));
+    }
+    match index.parse() {
+        Ok(index) if index <= MAX_INDEX => Ok(index),
+        _ => Err(format!("The bit index {} is past bit {}, the highest a field can have", index, MAX_INDEX)),
     }
 }
 
@@ -309,11 +975,85 @@ pub fn parse_literal(string: &str) -> Literal {
     }
 }
 
+/// Lexes a literal with an explicit width, taking in groups of digits
+/// after a `_` only while they fit in the width. Anything after that
+/// is lexed on its own, so `7'h00_0000000` is a 7 bit literal followed
+/// by 7 literal bits.
+fn lex_sized_literal(lex: &mut logos::Lexer<Token>) -> Option<Literal> {
+    let (width, rest) = lex.slice().split_once('\'')?;
+    let bits = width.parse().ok().filter(|bits| (1..=128).contains(bits))?;
+    let (radix, _) = literal_radix(rest.chars().next()?)?;
+    let capacity = digit_capacity(bits, radix);
+    let mut digits = rest.len() - 1;
+    loop {
+        let group = match lex.remainder().strip_prefix('_') {
+            Some(group) => group.find(|chr: char| !chr.is_ascii_hexdigit()).unwrap_or(group.len()),
+            None => break,
+        };
+        if group == 0 || digits + group > capacity {
+            break;
+        }
+        lex.bump(group + 1);
+        digits += group;
+    }
+    parse_sized_literal(lex.slice()).ok()
+}
+
+/// The radix a literal's radix letter stands for, and its name.
+fn literal_radix(letter: char) -> Option<(u32, &'static str)> {
+    match letter.to_ascii_lowercase() {
+        'b' => Some((2, "binary")),
+        'o' => Some((8, "octal")),
+        'd' => Some((10, "decimal")),
+        'h' => Some((16, "hex")),
+        _ => None,
+    }
+}
+
+/// The most digits in `radix` that a literal `bits` wide can have.
+fn digit_capacity(bits: usize, radix: u32) -> usize {
+    match radix {
+        10 => (u128::MAX >> (128 - bits)).to_string().len(),
+        _ => bits.div_ceil(radix.trailing_zeros() as usize),
+    }
+}
+
+/// Parses a literal with an explicit width, such as `7'h33`. The
+/// radix is one of `b`, `o`, `d` or `h`, the digits can be separated
+/// with `_`, and they have to fit in the width.
+pub fn parse_sized_literal(string: &str) -> Result<Literal, String> {
+    let (width, rest) = match string.split_once('\'') {
+        Some(parts) => parts,
+        None => return Err("Expected a literal like 7'h33".to_string()),
+    };
+    let bits: usize = match width.parse() {
+        Ok(bits) if (1..=128).contains(&bits) => bits,
+        _ => return Err(format!("The width of a literal has to be from 1 to 128 bits, but it's {}", width)),
+    };
+    let (radix, name) = match rest.chars().next().and_then(literal_radix) {
+        Some(radix) => radix,
+        None => return Err("Expected the radix of the literal to be b, o, d or h, as in 7'h33".to_string()),
+    };
+    let digits = &rest[1..];
+    if digits.is_empty() || digits.starts_with('_') || !digits.chars().all(|chr| chr.is_digit(radix) || chr == '_') {
+        return Err(format!("Expected {} digits after the radix, but found \"{}\"", name, digits));
+    }
+    let grouped = digits.replace('_', "");
+    let value = match u128::from_str_radix(&grouped, radix) {
+        Ok(value) if grouped.len() <= digit_capacity(bits, radix) && (bits == 128 || value >> bits == 0) => value,
+        _ => return Err(format!("{} doesn't fit in {} bits", digits, bits)),
+    };
+    Ok(Literal {
+        bytes: value.to_le_bytes()[..((bits + 7) / 8)].to_vec(),
+        bits,
+    })
+}
+
 pub fn parse_bits(bits: &str) -> Vec<u8> {
     let mut results = vec![];
     let mut len = bits.len();
     while len > 8 {
-        let end = &bits[(len - 8)..];
+        let end = &bits[(len - 8)..len];
         results.push(parse_bits_to_byte(end));
         len -= 8;
     }
@@ -333,3 +1073,94 @@ pub fn parse_bits_to_byte(bits: &str) -> u8 {
     }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern_error(pattern: &str) -> String {
+        parse_bit_pattern(&PatternSpan::new(Span::call_site()), pattern).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_range_end_precedes_start() {
+        assert_eq!(
+            parse_range("[x:7-0]"),
+            Err("The range end 0 precedes its start 7. Ranges are written low bit first, as in [x:0-7]".to_string()),
+        );
+        assert_eq!(
+            pattern_error("1010 [x:7-0]"),
+            "The range end 0 precedes its start 7. Ranges are written low bit first, as in [x:0-7] (`[x:7-0]` at 5..12 in the pattern)",
+        );
+    }
+
+    #[test]
+    fn test_range_index_too_high() {
+        assert_eq!(
+            parse_range("[x:99999999999999999999999]"),
+            Err("The bit index 99999999999999999999999 is past bit 127, the highest a field can have".to_string()),
+        );
+        assert_eq!(
+            parse_range("[x:0-18446744073709551614]"),
+            Err("The bit index 18446744073709551614 is past bit 127, the highest a field can have".to_string()),
+        );
+        assert_eq!(parse_range("[x:0-127]").map(|range| range.len), Ok(128));
+        assert_eq!(
+            pattern_error("[x:128]"),
+            "The bit index 128 is past bit 127, the highest a field can have (`[x:128]` at 0..7 in the pattern)",
+        );
+    }
+
+    #[test]
+    fn test_sized_literal_bad_radix() {
+        assert_eq!(
+            parse_sized_literal("7'q33"),
+            Err("Expected the radix of the literal to be b, o, d or h, as in 7'h33".to_string()),
+        );
+        assert_eq!(
+            pattern_error("1 | 7'q33"),
+            "Expected the radix of the literal to be b, o, d or h, as in 7'h33 (`7'q33` at 4..9 in the pattern)",
+        );
+    }
+
+    #[test]
+    fn test_sized_literal_too_wide() {
+        assert_eq!(parse_sized_literal("4'h1F"), Err("1F doesn't fit in 4 bits".to_string()));
+        assert_eq!(
+            pattern_error("[x:0-3] 4'h1F"),
+            "1F doesn't fit in 4 bits (`4'h1F` at 8..13 in the pattern)",
+        );
+        assert_eq!(parse_sized_literal("4'b01010"), Err("01010 doesn't fit in 4 bits".to_string()));
+    }
+
+    #[test]
+    fn test_sized_literal_groups_stop_at_width() {
+        let bits = |pattern| parse_bit_pattern(&PatternSpan::new(Span::call_site()), pattern).unwrap().bits();
+        assert_eq!(bits("12'h5_55"), 12);
+        assert_eq!(bits("8'd2_55"), 8);
+        assert_eq!(bits("7'h00_0000000_[x:0-4]"), 19);
+        assert_eq!(bits("4'b10_10_1"), 5);
+    }
+
+    #[test]
+    fn test_mapping_with_bad_transform() {
+        let error = syn::parse_str::<BitPattern>(r#""[imm:0-3]", imm = offset * 2"#).err().unwrap();
+        assert_eq!(error.to_string(), "Expected one of >>, /, - or + in a field mapping");
+        let error = syn::parse_str::<BitPattern>(r#""[imm:0-3]", imm = (offset >> 1) % 4"#).err().unwrap();
+        assert_eq!(error.to_string(), "Expected one of >>, /, - or + in a field mapping");
+
+        let pattern = syn::parse_str::<BitPattern>(r#""[imm:0-3]", imm = { offset * 2 }"#).unwrap();
+        assert!(pattern.is_computed("imm"));
+        let pattern = syn::parse_str::<BitPattern>(r#""[sum:0-3]", sum = a + b"#).unwrap();
+        assert!(pattern.is_computed("sum"));
+    }
+
+    #[test]
+    fn test_range_offsets() {
+        let pattern = parse_bit_pattern(&PatternSpan::new(Span::call_site()), "1011 [x:0-3:s] [x:4]").unwrap();
+        assert_eq!(
+            pattern.sign_bits().unwrap_err().to_string(),
+            "The signed range for \"x\" ends at bit 3, but bit 4 of it is also used. Only the range with the highest bit can be signed. (`[x:0-3:s]` at 5..14 in the pattern)",
+        );
+    }
+}
@@ -1,90 +1,163 @@
-
 use std::collections::HashMap;
 
 use proc_macro2::{TokenStream as TokenStreamInternal, Ident};
-use syn::{DeriveInput, spanned::Spanned, Expr, DataEnum};
+use syn::{DeriveInput, spanned::Spanned, Expr};
 use quote::quote;
 
-use crate::bitpattern::{Range, Token, RawMappingValue, Literal, BitPattern, compute_mapping, find_bit_pattern};
+use crate::{bitpattern::{Range, Token, RawMappingValue, Literal, BitPattern, compute_mapping, find_bit_pattern, range_expr}, common::{generics, generics_names, where_clause_with, sign_extend_constraints, range_width_checks}};
 
 const GENERIC_FAILURE: &str = r#"#[derive(BitSink)] expects an attribute of the form #[bitpattern("11010[a:0-2]0110", a=foo)]"#;
 
 pub fn expand_bit_sink(ast: &DeriveInput) -> syn::Result<TokenStreamInternal> {
-    Err(syn::Error::new(ast.span(), "#[derive(BitSink)] is not yet implemented."))
+    let type_ident = &ast.ident;
+    let type_name = ast.ident.to_string();
+    let mut width_checks = vec![];
+    let mut generic_width_checks = vec![];
+    let (body, size_body, extra_constraints) = match &ast.data {
+        syn::Data::Struct(data_struct) => {
+            if let Some(pattern) = find_bit_pattern(&ast.attrs, &ast.attrs, &data_struct.fields)? {
+                if !pattern.transforms.is_empty() {
+                    return Err(syn::Error::new(pattern.span, "#[derive(BitSink)] doesn't support field transforms, since a field may be written in several parts. Use #[derive(BitDecode)] instead."));
+                }
+                if let Some(path) = pattern.codecs.values().next() {
+                    return Err(syn::Error::new(path.span(), "#[derive(BitSink)] doesn't support with codecs, since a field may be written in several parts. Use #[derive(BitDecode)] instead."));
+                }
+                let fields = &data_struct.fields;
+                let mapping = compute_mapping(fields, &pattern)?;
+                let (items, statements) = range_width_checks(ast, &type_name, fields, &pattern)?;
+                width_checks.extend(items);
+                generic_width_checks.extend(statements);
+                let field_bindings = {
+                    let mut bindings = vec![];
+                    let mut index = 0;
+                    for field in fields {
+                        if let Some(ident) = &field.ident {
+                            let new_ident = Ident::new_raw(&format!("____{}", ident), ident.span());
+                            bindings.push(quote!{ let #new_ident = &mut target.#ident; });
+                        } else {
+                            let ident = Ident::new_raw(&format!("_____{}", index), field.span());
+                            let index = syn::Index::from(index);
+                            bindings.push(quote! { let #ident = &mut target.#index; });
+                        };
+                        index += 1;
+                    }
+                    quote! {
+                        #(#bindings)*
+                    }
+                };
+                let block = write_sink_block(&field_bindings, &pattern, &mapping)?;
+                let body = quote! {
+                    ::decree::check_output_range(#type_name, pos, len, ::decree::BitSink::size(target))?;
+                    #block
+                };
+                let pattern_bits = pattern.bits();
+                let size_body = quote! {
+                    Some(#pattern_bits)
+                };
+                (body, size_body, sign_extend_constraints(fields, &pattern)?)
+            } else {
+                return Err(syn::Error::new(ast.ident.span(), GENERIC_FAILURE))
+            }
+        },
+        syn::Data::Enum(_) => return Err(syn::Error::new(ast.span(), "#[derive(BitSink)] is not yet supported for enums.")),
+        syn::Data::Union(_) => return Err(syn::Error::new(ast.span(), "#[derive(BitSink)] is not supported for untagged unions.")),
+    };
+    let generics = generics(ast);
+    let generics_names = generics_names(ast);
+    let where_clause = where_clause_with(ast, &quote! { ::decree::BitSink }, extra_constraints)?;
+    Ok(quote! {
+        impl #generics ::decree::BitSink for #type_ident #generics_names #where_clause {
+            fn write(&mut self, bytes: &[u8], start: usize, len: usize, pos: usize, endianness: ::decree::Endianness) -> core::result::Result<usize, ::decree::Error> {
+                #(#generic_width_checks)*
+                if len == 0 {
+                    return Ok(0);
+                }
+                #[inline]
+                fn write_inner #generics (target: &mut #type_ident #generics_names, bytes: &[u8], start: usize, len: usize, pos: usize, endianness: ::decree::Endianness) -> core::result::Result<usize, ::decree::Error> #where_clause {
+                    #body
+                }
+                write_inner(self, bytes, start, len, pos, endianness)
+                    .map_err(|err| err.write_failed(
+                        format!(
+                            "Failed to write {} bits to [{}, {}] in {}.",
+                            len,
+                            pos,
+                            pos + len - 1,
+                            #type_name
+                        )
+                    ))
+            }
+
+            fn size(&self) -> Option<usize> {
+                #size_body
+            }
+        }
+
+        #(#width_checks)*
+    })
 }
 
+fn write_sink_block(field_bindings: &TokenStreamInternal, pattern: &BitPattern, mapping: &HashMap<RawMappingValue, Expr>) -> syn::Result<TokenStreamInternal> {
+    let writes = {
+        let mut writes = Vec::new();
+        let mut token_start = 0;
+        for token in pattern.tokens.iter().rev() {
+            match token {
+                Token::Bits(Literal { bits, .. }) | Token::Reserved(Literal { bits, .. }) => {
+                    token_start += bits;
+                },
+                Token::DontCare(bits) => {
+                    token_start += bits;
+                },
+                Token::Range(Range { name, len, .. }) if pattern.is_computed(name) => {
+                    token_start += len;
+                },
+                Token::Range(range @ Range { start: range_start, len, signed, .. }) => {
+                    let token_end = token_start + len;
+                    let target_expr = range_expr(pattern, mapping, range)?;
+                    let sign_extend = if *signed {
+                        let sign_bit = range_start + len - 1;
+                        quote! {
+                            if to == #token_end {
+                                ::decree::SignExtend::sign_extend(target, #sign_bit);
+                            }
+                        }
+                    } else {
+                        quote! {}
+                    };
+                    let tokens = quote! {
+                        if pos < #token_end && end > #token_start {
+                            let from = usize::max(pos, #token_start);
+                            let to = usize::min(end, #token_end);
+                            let target = {
+                                #field_bindings
+                                #target_expr
+                            };
+                            ::decree::BitSink::write(
+                                target,
+                                bytes,
+                                start + (from - pos),
+                                to - from,
+                                #range_start + (from - #token_start),
+                                endianness
+                            )?;
+                            #sign_extend
+                        }
+                    };
+                    writes.push(tokens);
+                    token_start += len;
+                },
+                Token::Error => return Err(syn::Error::new(pattern.span, "The pattern has a token that couldn't be read")),
+            }
+        }
+        writes
+    };
+    let tokens = quote! {
+        let end = pos + len;
 
-// fn write_source_block(field_bindings: &TokenStreamInternal, pattern: &BitPattern, mapping: &HashMap<RawMappingValue, Expr>) -> syn::Result<TokenStreamInternal> {
-//     let writes = {
-//         let mut writes = Vec::new();
-//         let mut token_start = 0;
-//         for token in pattern.tokens.iter().rev() {
-//             match token {
-//                 Token::Bits(Literal { bytes, bits }) => {
-//                     let num_bytes = bytes.len();
-//                     let byte_array_tokens = byte_array_tokens(bytes);
-//                     let token_end = token_start + bits;
-//                     let tokens = quote! {
-//                         if start < #token_end && end >= #token_start {
-//                             static source_bytes: [u8; #num_bytes] = #byte_array_tokens;
-//                             let source = ::decree::LittleEndian::<&[u8]>::with_bits(source_bytes.as_ref(), #bits)?;
-//                             let written = source.write(sink, start - #token_start, usize::min(#bits, len), pos)?;
-//                             start += written;
-//                             pos += written;
-//                             bits_written += written;
-//                             len -= written;
-//                         }
-//                     };
-//                     writes.push(tokens);
-//                     token_start += bits;
-//                 },
-//                 Token::Range(Range { name, start: range_start, len }) => {
-//                     let token_end = token_start + len;
-//                     let source_expr = {
-//                         let name_value = RawMappingValue::Name(name.to_string());
-//                         if mapping.contains_key(&name_value) {
-//                             &mapping[&name_value]
-//                         } else {
-//                             let index: usize = name.parse().unwrap();
-//                             &mapping[&RawMappingValue::Index(index)]
-//                         }
-//                     };
-//                     let tokens = quote! {
-//                         if start < #token_end && end >= #token_start {
-//                             let source = {
-//                                 #field_bindings
-//                                 #source_expr
-//                             };
-//                             let written = source.write(
-//                                 sink,
-//                                 start - #token_start + #range_start,
-//                                 usize::min(#len, len),
-//                                 pos
-//                             )?;
-//                             start += written;
-//                             pos += written;
-//                             bits_written += written;
-//                             len -= written;
-//                         }
-//                     };
-//                     writes.push(tokens);
-//                     token_start += len;
-//                 },
-//                 Token::Error => unimplemented!(),
-//             }
-//         }
-//         writes
-//     };
-//     let tokens = quote! {
-//         let end = start + len;
-//         let mut bits_written = 0;
-//         let mut start = start;
-//         let mut len = len;
-//         let mut pos = pos;
+        #(#writes)*
 
-//         #(#writes)*
-        
-//         Ok(bits_written)
-//     };
-//     Ok(tokens)
-// }
+        Ok(len)
+    };
+    Ok(tokens)
+}
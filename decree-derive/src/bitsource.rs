@@ -5,18 +5,23 @@ use proc_macro2::{TokenStream as TokenStreamInternal, Ident};
 use syn::{DeriveInput, spanned::Spanned, Expr, DataEnum};
 use quote::quote;
 
-use crate::{bitpattern::{Range, Token, RawMappingValue, Literal, BitPattern, compute_mapping, find_bit_pattern}, common::{collect_types, type_constraints, generics, generics_names, where_clause}};
+use crate::{bitpattern::{Range, Token, RawMappingValue, Literal, BitPattern, compute_mapping, find_bit_pattern}, common::{generics, generics_names, where_clause, byte_array_tokens, range_width_checks}, transform::encode_tokens};
 
 const GENERIC_FAILURE: &str = r#"#[derive(BitSource)] expects an attribute of the form #[bitpattern("11010[a:0-2]0110", a=foo)]"#;
 
 pub fn expand_bit_source(ast: &DeriveInput) -> syn::Result<TokenStreamInternal> {
     let type_ident = &ast.ident;
     let type_name = ast.ident.to_string();
+    let mut width_checks = vec![];
+    let mut generic_width_checks = vec![];
     let (body, size_body) = match &ast.data {
         syn::Data::Struct(data_struct) => {
-            if let Some(pattern) = find_bit_pattern(&ast.attrs)? {
+            if let Some(pattern) = find_bit_pattern(&ast.attrs, &ast.attrs, &data_struct.fields)? {
                 let fields = &data_struct.fields;
                 let mapping = compute_mapping(fields, &pattern)?;
+                let (items, statements) = range_width_checks(ast, &type_name, fields, &pattern)?;
+                width_checks.extend(items);
+                generic_width_checks.extend(statements);
                 let field_bindings = {
                     let mut bindings = vec![];
                     let mut index = 0;
@@ -35,9 +40,11 @@ pub fn expand_bit_source(ast: &DeriveInput) -> syn::Result<TokenStreamInternal>
                         #(#bindings)*
                     }
                 };
-                let block = write_source_block(&field_bindings, &pattern, &mapping)?;
+                let block = write_source_block(&type_name, &field_bindings, &pattern, &mapping)?;
+                let requirement = requirement_check(&type_name, &field_bindings, &pattern, &mapping);
                 let body = quote! {
                     ::decree::check_input_range(#type_name, start, len, 0, ::decree::BitSource::size(source))?;
+                    #requirement
                     #block
                 };
                 let pattern_bits = pattern.bits();
@@ -54,7 +61,7 @@ pub fn expand_bit_source(ast: &DeriveInput) -> syn::Result<TokenStreamInternal>
                 let mut sizes = vec![];
                 let mut matches = vec![];
                 for variant in variants {
-                    if let Some(pattern) = find_bit_pattern(&variant.attrs)? {
+                    if let Some(pattern) = find_bit_pattern(&ast.attrs, &variant.attrs, &variant.fields)? {
                         let enum_type = &ast.ident;
                         let variant_ident = &variant.ident;
                         let mut indexed = false;
@@ -88,10 +95,16 @@ pub fn expand_bit_source(ast: &DeriveInput) -> syn::Result<TokenStreamInternal>
                             }
                         };
                         let mapping = compute_mapping(&variant.fields, &pattern)?;
-                        let block = write_source_block(&quote!{}, &pattern, &mapping)?;
+                        let target = format!("{}::{}", type_name, variant_ident);
+                        let (items, statements) = range_width_checks(ast, &target, &variant.fields, &pattern)?;
+                        width_checks.extend(items);
+                        generic_width_checks.extend(statements);
+                        let block = write_source_block(&target, &quote!{}, &pattern, &mapping)?;
+                        let requirement = requirement_check(&target, &quote!{}, &pattern, &mapping);
                         let tokens = quote! {
                             #enum_type::#variant_ident #bindings => {
                                 ::decree::check_input_range(#type_name, start, len, 0, ::decree::BitSource::size(source))?;
+                                #requirement
                                 #block
                             }
                         };
@@ -129,6 +142,7 @@ pub fn expand_bit_source(ast: &DeriveInput) -> syn::Result<TokenStreamInternal>
     Ok(quote! {
         impl #generics ::decree::BitSource for #type_ident #generics_names #where_clause {
             fn write(&self, sink: &mut (impl ::decree::BitSink + ?Sized), start: usize, len: usize, pos: usize) -> core::result::Result<usize, ::decree::Error> {
+                #(#generic_width_checks)*
                 if len == 0 {
                     return Ok(0);
                 }
@@ -153,34 +167,29 @@ pub fn expand_bit_source(ast: &DeriveInput) -> syn::Result<TokenStreamInternal>
                 #size_body
             }
         }
+
+        #(#width_checks)*
     })
 }
 
-fn write_source_block(field_bindings: &TokenStreamInternal, pattern: &BitPattern, mapping: &HashMap<RawMappingValue, Expr>) -> syn::Result<TokenStreamInternal> {
+fn write_source_block(target: &str, field_bindings: &TokenStreamInternal, pattern: &BitPattern, mapping: &HashMap<RawMappingValue, Expr>) -> syn::Result<TokenStreamInternal> {
+    let used_bits = pattern.used_bits();
+    let sign_bits = pattern.sign_bits()?;
     let writes = {
         let mut writes = Vec::new();
         let mut token_start = 0;
         for token in pattern.tokens.iter().rev() {
             match token {
-                Token::Bits(Literal { bytes, bits }) => {
-                    let num_bytes = bytes.len();
-                    let byte_array_tokens = byte_array_tokens(bytes);
-                    let token_end = token_start + bits;
-                    let tokens = quote! {
-                        if start < #token_end && end >= #token_start {
-                            static source_bytes: [u8; #num_bytes] = #byte_array_tokens;
-                            let source = ::decree::LittleEndian::<&[u8]>::with_bits(source_bytes.as_ref(), #bits)?;
-                            let written = source.write(sink, start - #token_start, usize::min(#bits, len), pos)?;
-                            start += written;
-                            pos += written;
-                            bits_written += written;
-                            len -= written;
-                        }
-                    };
-                    writes.push(tokens);
+                Token::Bits(Literal { bytes, bits }) | Token::Reserved(Literal { bytes, bits }) => {
+                    writes.push(literal_tokens(bytes, *bits, token_start));
+                    token_start += bits;
+                },
+                Token::DontCare(bits) => {
+                    let fill = if pattern.dont_care { 0xFF } else { 0 };
+                    writes.push(literal_tokens(&vec![fill; (bits + 7) / 8], *bits, token_start));
                     token_start += bits;
                 },
-                Token::Range(Range { name, start: range_start, len }) => {
+                Token::Range(Range { name, start: range_start, len, .. }) => {
                     let token_end = token_start + len;
                     let source_expr = {
                         let name_value = RawMappingValue::Name(name.to_string());
@@ -191,16 +200,48 @@ fn write_source_block(field_bindings: &TokenStreamInternal, pattern: &BitPattern
                             &mapping[&RawMappingValue::Index(index)]
                         }
                     };
-                    let tokens = quote! {
-                        if start < #token_end && end >= #token_start {
-                            let source = {
+                    let field = pattern.resolve(name);
+                    let source = match (pattern.transforms.get(&field), pattern.codecs.get(&field)) {
+                        _ if pattern.is_computed(name) => {
+                            let value = pattern.computed_value(name, mapping);
+                            quote! {
+                                &{
+                                    #field_bindings
+                                    #value
+                                }
+                            }
+                        },
+                        (_, Some(path)) => quote! {
+                            &{
+                                #field_bindings
+                                #path::encode(#source_expr)?
+                            }
+                        },
+                        (Some(transforms), None) => {
+                            let steps = encode_tokens(transforms, target, &field.to_string(), used_bits[&field], sign_bits.contains_key(&field));
+                            quote! {
+                                &{
+                                    #field_bindings
+                                    let value = *#source_expr;
+                                    #steps
+                                    value
+                                }
+                            }
+                        },
+                        (None, None) => quote! {
+                            {
                                 #field_bindings
                                 #source_expr
-                            };
+                            }
+                        },
+                    };
+                    let tokens = quote! {
+                        if start < #token_end && end >= #token_start {
+                            let source = #source;
                             let written = source.write(
                                 sink,
                                 start - #token_start + #range_start,
-                                usize::min(#len, len),
+                                usize::min(#token_end - start, len),
                                 pos
                             )?;
                             start += written;
@@ -231,15 +272,36 @@ fn write_source_block(field_bindings: &TokenStreamInternal, pattern: &BitPattern
     Ok(tokens)
 }
 
-fn byte_array_tokens(bytes: &[u8]) -> TokenStreamInternal {
-    let byte_tokens = {
-        let mut tokens = vec![];
-        for byte in bytes {
-            tokens.push(quote! { #byte });
-        }
-        tokens
-    };
+fn requirement_check(target: &str, field_bindings: &TokenStreamInternal, pattern: &BitPattern, mapping: &HashMap<RawMappingValue, Expr>) -> TokenStreamInternal {
+    match pattern.requirement(mapping, true) {
+        Some(requirement) => {
+            let requirement_string = pattern.requirement_string();
+            quote! {
+                {
+                    #field_bindings
+                    if !(#requirement) {
+                        return Err(::decree::Error::requirement_failed(#target, #requirement_string));
+                    }
+                }
+            }
+        },
+        None => quote! {},
+    }
+}
+
+fn literal_tokens(bytes: &[u8], bits: usize, token_start: usize) -> TokenStreamInternal {
+    let num_bytes = bytes.len();
+    let byte_array_tokens = byte_array_tokens(bytes);
+    let token_end = token_start + bits;
     quote! {
-        [#(#byte_tokens),*]
+        if start < #token_end && end >= #token_start {
+            static source_bytes: [u8; #num_bytes] = #byte_array_tokens;
+            let source = ::decree::LittleEndian::<&[u8]>::with_bits(source_bytes.as_ref(), #bits)?;
+            let written = source.write(sink, start - #token_start, usize::min(#token_end - start, len), pos)?;
+            start += written;
+            pos += written;
+            bits_written += written;
+            len -= written;
+        }
     }
 }
@@ -1,14 +1,20 @@
-use std::collections::HashSet;
+use std::collections::{HashSet, HashMap};
 
-use syn::{DeriveInput, Type, DataEnum};
-use proc_macro2::TokenStream as TokenStreamInternal;
-use quote::quote;
+use syn::{DeriveInput, Type, DataEnum, Fields, Generics, TypeParamBound, WherePredicate, spanned::Spanned};
+use proc_macro2::{TokenStream as TokenStreamInternal, TokenTree};
+use quote::{quote, quote_spanned};
 
-use crate::bitpattern::{find_bit_pattern, RawMappingValue};
+use crate::bitpattern::{find_bit_pattern, BitPattern, RawMappingValue};
 
 pub fn where_clause(ast: &DeriveInput, constraints: &TokenStreamInternal) -> syn::Result<TokenStreamInternal> {
+    where_clause_with(ast, constraints, vec![])
+}
+
+/// Like `where_clause`, with `extra` constraints added on the end.
+pub fn where_clause_with(ast: &DeriveInput, constraints: &TokenStreamInternal, extra: Vec<TokenStreamInternal>) -> syn::Result<TokenStreamInternal> {
     let types = collect_types(ast)?;
-    let constraints = type_constraints(&types, constraints);
+    let mut constraints = type_constraints(&types, constraints);
+    constraints.extend(extra);
     let where_clause = match &ast.generics.where_clause {
         Some(clause) => quote! {
             #clause, #(#constraints),*
@@ -24,18 +30,18 @@ pub fn collect_types(ast: &DeriveInput) -> syn::Result<HashSet<Type>> {
     let mut types = HashSet::new();
     match &ast.data {
         syn::Data::Struct(data_struct) => {
-            let bitpattern = find_bit_pattern(&ast.attrs)?.expect("Expected bitpattern to exist");
+            let bitpattern = find_bit_pattern(&ast.attrs, &ast.attrs, &data_struct.fields)?.expect("Expected bitpattern to exist");
             let values = bitpattern.referenced_values();
             let mut index = 0;
             for field in &data_struct.fields {
                 if let Some(ident) = &field.ident {
                     let name = RawMappingValue::Name(ident.to_string());
-                    if values.contains(&name) {
+                    if values.contains(&name) && !bitpattern.codecs.contains_key(&name) {
                         types.insert(field.ty.clone());
                     }
                 } else {
                     let name = RawMappingValue::Index(index);
-                    if values.contains(&name) {
+                    if values.contains(&name) && !bitpattern.codecs.contains_key(&name) {
                         types.insert(field.ty.clone());
                     }
                 }
@@ -44,18 +50,18 @@ pub fn collect_types(ast: &DeriveInput) -> syn::Result<HashSet<Type>> {
         },
         syn::Data::Enum(DataEnum { variants, .. }) => {
             for variant in variants {
-                let bitpattern = find_bit_pattern(&variant.attrs)?.expect("Expected bitpattern to exist");
+                let bitpattern = find_bit_pattern(&ast.attrs, &variant.attrs, &variant.fields)?.expect("Expected bitpattern to exist");
                 let values = bitpattern.referenced_values();
                 let mut index = 0;
                 for field in &variant.fields {
                     if let Some(ident) = &field.ident {
                         let name = RawMappingValue::Name(ident.to_string());
-                        if values.contains(&name) {
+                        if values.contains(&name) && !bitpattern.codecs.contains_key(&name) {
                             types.insert(field.ty.clone());
                         }
                     } else {
                         let name = RawMappingValue::Index(index);
-                        if values.contains(&name) {
+                        if values.contains(&name) && !bitpattern.codecs.contains_key(&name) {
                             types.insert(field.ty.clone());
                         }
                     }
@@ -68,6 +74,132 @@ pub fn collect_types(ast: &DeriveInput) -> syn::Result<HashSet<Type>> {
     Ok(types)
 }
 
+pub fn field_types(fields: &Fields) -> HashMap<RawMappingValue, Type> {
+    let mut types = HashMap::new();
+    for (index, field) in fields.iter().enumerate() {
+        let name = match &field.ident {
+            Some(ident) => RawMappingValue::Name(ident.to_string()),
+            None => RawMappingValue::Index(index),
+        };
+        types.insert(name, field.ty.clone());
+    }
+    types
+}
+
+/// The widths of the primitive types, which can be checked without
+/// waiting for the compiler.
+fn primitive_bits(ty: &Type) -> Option<usize> {
+    let ident = match ty {
+        Type::Path(path) if path.qself.is_none() => path.path.get_ident()?,
+        _ => return None,
+    };
+    match ident.to_string().as_str() {
+        "u8" | "i8" => Some(8),
+        "u16" | "i16" => Some(16),
+        "u32" | "i32" => Some(32),
+        "u64" | "i64" => Some(64),
+        "u128" | "i128" => Some(128),
+        _ => None,
+    }
+}
+
+fn mentions(tokens: TokenStreamInternal, names: &HashSet<String>) -> bool {
+    tokens.into_iter().any(|tree| match tree {
+        TokenTree::Ident(ident) => names.contains(&ident.to_string()),
+        TokenTree::Group(group) => mentions(group.stream(), names),
+        _ => false,
+    })
+}
+
+/// Whether `ty` is bounded by `FixedBitSize`, either where it's
+/// declared as a type parameter or in the where clause.
+fn has_fixed_bit_size_bound(generics: &Generics, ty: &Type) -> bool {
+    let is_fixed_bit_size = |bound: &TypeParamBound| matches!(bound, TypeParamBound::Trait(bound) if bound.path.segments.last().is_some_and(|segment| segment.ident == "FixedBitSize"));
+    let ty = quote! { #ty }.to_string();
+    let declared = generics.type_params()
+        .any(|param| param.ident == ty && param.bounds.iter().any(is_fixed_bit_size));
+    let constrained = generics.where_clause.iter()
+        .flat_map(|clause| clause.predicates.iter())
+        .any(|predicate| match predicate {
+            WherePredicate::Type(predicate) => {
+                let bounded = &predicate.bounded_ty;
+                quote! { #bounded }.to_string() == ty && predicate.bounds.iter().any(is_fixed_bit_size)
+            },
+            _ => false,
+        });
+    declared || constrained
+}
+
+/// Checks that the ranges for each field fit in its type. Primitive
+/// fields are checked here. Other fields get an assertion that fails
+/// to compile if their type has a `FixedBitSize` narrower than the
+/// ranges. Returns the assertions for types that are known outside
+/// the impl, as items, and for ones that use the type's generics, as
+/// statements for a method body. Inside the impl, the compiler only
+/// knows a generic type has a `FixedBitSize` if it's bounded by one,
+/// so ranges over fields with unbounded generic types are an error.
+pub fn range_width_checks(ast: &DeriveInput, target: &str, fields: &Fields, pattern: &BitPattern) -> syn::Result<(Vec<TokenStreamInternal>, Vec<TokenStreamInternal>)> {
+    let types = field_types(fields);
+    let generics: HashSet<String> = ast.generics.type_params()
+        .map(|param| param.ident.to_string())
+        .collect();
+    let mut items = vec![];
+    let mut statements = vec![];
+    for (value, used) in pattern.used_bits() {
+        if pattern.codecs.contains_key(&value) {
+            continue;
+        }
+        let ty = match types.get(&value) {
+            Some(ty) => ty,
+            None => continue,
+        };
+        if let Some(bits) = primitive_bits(ty) {
+            if used > bits {
+                return Err(syn::Error::new(ty.span(), format!("The pattern for {} uses bit {} of \"{}\", but {} only has {} bits", target, used - 1, value, quote! { #ty }, bits)));
+            }
+            continue;
+        }
+        let message = format!("The pattern for {} uses bit {} of \"{}\", but its type has fewer bits", target, used - 1, value);
+        let assertion = quote_spanned! { ty.span() =>
+            {
+                #[allow(unused_imports)]
+                use ::decree::UnknownBitSize as _;
+                assert!(
+                    match ::decree::BitSizeProbe::<#ty>::BITS {
+                        Some(bits) => bits >= #used,
+                        None => true,
+                    },
+                    #message
+                );
+            }
+        };
+        if mentions(quote! { #ty }, &generics) {
+            if !has_fixed_bit_size_bound(&ast.generics, ty) {
+                return Err(syn::Error::new(ty.span(), format!("The pattern for {} uses bits of \"{}\", but its type is generic, so they can't be checked. Add a bound like `{}: FixedBitSize`.", target, value, quote! { #ty })));
+            }
+            statements.push(quote! { const #assertion; });
+        } else {
+            items.push(quote! { const _: () = #assertion; });
+        }
+    }
+    Ok((items, statements))
+}
+
+/// `SignExtend` constraints for the fields that have a signed range.
+pub fn sign_extend_constraints(fields: &Fields, pattern: &BitPattern) -> syn::Result<Vec<TokenStreamInternal>> {
+    let types = field_types(fields);
+    let mut constraints = vec![];
+    for value in pattern.sign_bits()?.keys() {
+        if pattern.codecs.contains_key(value) {
+            continue;
+        }
+        if let Some(ty) = types.get(value) {
+            constraints.push(quote! { #ty: ::decree::SignExtend });
+        }
+    }
+    Ok(constraints)
+}
+
 pub fn type_constraints(types: &HashSet<Type>, constraints: &TokenStreamInternal) -> Vec<TokenStreamInternal> {
     let mut results = vec![];
     for ty in types {
@@ -106,3 +238,33 @@ pub fn generics_names(ast: &DeriveInput) -> TokenStreamInternal {
         }
     }
 }
+
+pub fn byte_array_tokens(bytes: &[u8]) -> TokenStreamInternal {
+    let byte_tokens = {
+        let mut tokens = vec![];
+        for byte in bytes {
+            tokens.push(quote! { #byte });
+        }
+        tokens
+    };
+    quote! {
+        [#(#byte_tokens),*]
+    }
+}
+
+/// Converts a variant name like `CAddi4spn` to `C_ADDI4SPN`.
+pub fn screaming_snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut result = String::new();
+    for (index, chr) in chars.iter().enumerate() {
+        if index > 0 && chr.is_uppercase() {
+            let previous = chars[index - 1];
+            let next_lowercase = chars.get(index + 1).is_some_and(|next| next.is_lowercase());
+            if previous.is_lowercase() || previous.is_ascii_digit() || (previous.is_uppercase() && next_lowercase) {
+                result.push('_');
+            }
+        }
+        result.extend(chr.to_uppercase());
+    }
+    result
+}
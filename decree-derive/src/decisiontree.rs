@@ -0,0 +1,134 @@
+use crate::bitpattern::FixedBits;
+
+/// A tree of single-bit tests that narrows the set of patterns that
+/// can match an input. Leaves list the remaining candidates in
+/// declaration order, each of which still has to be checked in full.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecisionTree {
+    Leaf(Vec<usize>),
+    Branch {
+        bit: usize,
+        zero: Box<DecisionTree>,
+        one: Box<DecisionTree>,
+    },
+}
+
+impl DecisionTree {
+    /// Builds a tree over `patterns`. Leaves keep their candidates in
+    /// the same relative order as `candidates`.
+    ///
+    /// Each branch splits on the bit that leaves the fewest candidates
+    /// on its larger side, preferring bits that fewer candidates leave
+    /// unfixed, since those end up on both sides.
+    pub fn build(patterns: &[FixedBits], candidates: Vec<usize>) -> DecisionTree {
+        if candidates.len() <= 1 {
+            return DecisionTree::Leaf(candidates);
+        }
+        let max_bits = candidates.iter()
+            .map(|index| patterns[*index].bits())
+            .max()
+            .unwrap_or(0);
+        let mut best: Option<(usize, usize, usize)> = None;
+        for bit in 0..max_bits {
+            let mut zeros = 0;
+            let mut ones = 0;
+            for index in &candidates {
+                match patterns[*index].get(bit) {
+                    Some(false) => zeros += 1,
+                    Some(true) => ones += 1,
+                    None => {
+                        zeros += 1;
+                        ones += 1;
+                    },
+                }
+            }
+            let largest = usize::max(zeros, ones);
+            if largest == candidates.len() {
+                continue;
+            }
+            let total = zeros + ones;
+            let better = match best {
+                Some((_, best_largest, best_total)) => (largest, total) < (best_largest, best_total),
+                None => true,
+            };
+            if better {
+                best = Some((bit, largest, total));
+            }
+        }
+        match best {
+            Some((bit, _, _)) => {
+                let (zero, one) = {
+                    let mut zero = vec![];
+                    let mut one = vec![];
+                    for index in candidates {
+                        match patterns[index].get(bit) {
+                            Some(false) => zero.push(index),
+                            Some(true) => one.push(index),
+                            None => {
+                                zero.push(index);
+                                one.push(index);
+                            },
+                        }
+                    }
+                    (zero, one)
+                };
+                DecisionTree::Branch {
+                    bit,
+                    zero: Box::new(DecisionTree::build(patterns, zero)),
+                    one: Box::new(DecisionTree::build(patterns, one)),
+                }
+            },
+            None => DecisionTree::Leaf(candidates),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixed(mask: u8, value: u8) -> FixedBits {
+        FixedBits { mask: vec![mask], value: vec![value] }
+    }
+
+    fn leaf(candidates: &[usize]) -> Box<DecisionTree> {
+        Box::new(DecisionTree::Leaf(candidates.to_vec()))
+    }
+
+    #[test]
+    fn test_split_on_most_even_bit() {
+        let patterns = [fixed(0b111, 0b000), fixed(0b111, 0b010), fixed(0b111, 0b100), fixed(0b111, 0b111)];
+        assert_eq!(
+            DecisionTree::build(&patterns, vec![0, 1, 2, 3]),
+            DecisionTree::Branch {
+                bit: 1,
+                zero: Box::new(DecisionTree::Branch { bit: 2, zero: leaf(&[0]), one: leaf(&[2]) }),
+                one: Box::new(DecisionTree::Branch { bit: 0, zero: leaf(&[1]), one: leaf(&[3]) }),
+            });
+    }
+
+    #[test]
+    fn test_split_prefers_fixed_bits() {
+        // Both bits leave two candidates on their larger side, but
+        // bit 1 is unfixed in the last pattern.
+        let patterns = [fixed(0b11, 0b00), fixed(0b11, 0b10), fixed(0b01, 0b01)];
+        assert_eq!(
+            DecisionTree::build(&patterns, vec![0, 1, 2]),
+            DecisionTree::Branch {
+                bit: 0,
+                zero: Box::new(DecisionTree::Branch { bit: 1, zero: leaf(&[0]), one: leaf(&[1]) }),
+                one: leaf(&[2]),
+            });
+    }
+
+    #[test]
+    fn test_overlapping_leaves_keep_candidate_order() {
+        let patterns = [fixed(0b0, 0b0), fixed(0b1, 0b1), fixed(0b1, 0b0)];
+        assert_eq!(
+            DecisionTree::build(&patterns, vec![0, 1, 2]),
+            DecisionTree::Branch { bit: 0, zero: leaf(&[0, 2]), one: leaf(&[0, 1]) });
+        assert_eq!(
+            DecisionTree::build(&patterns, vec![2, 1, 0]),
+            DecisionTree::Branch { bit: 0, zero: leaf(&[2, 0]), one: leaf(&[1, 0]) });
+    }
+}
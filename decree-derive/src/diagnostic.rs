@@ -0,0 +1,68 @@
+use std::ops::Range;
+
+use proc_macro2::{Literal, Span};
+use syn::LitStr;
+
+/// Where a pattern string was written, so errors in it can point at
+/// the part that's wrong rather than the whole attribute.
+#[derive(Debug, Clone)]
+pub struct PatternSpan {
+    span: Span,
+    /// The string literal the pattern was read from, if each byte of
+    /// the pattern is written as one byte of the literal.
+    literal: Option<Literal>,
+    /// Where the pattern starts in the literal's source text.
+    offset: usize,
+}
+
+impl PatternSpan {
+    /// A pattern that was built up rather than written out, so errors
+    /// can only point at `span`.
+    pub fn new(span: Span) -> Self {
+        PatternSpan { span, literal: None, offset: 0 }
+    }
+
+    /// A pattern read from `lit`. Literals with escapes don't map
+    /// onto their value byte for byte, so errors in them point at the
+    /// whole literal.
+    pub fn literal(lit: &LitStr) -> Self {
+        let literal = lit.token();
+        let source = literal.to_string();
+        let value = lit.value();
+        match source.find('"') {
+            Some(quote) if source[(quote + 1)..].starts_with(&value) => PatternSpan {
+                span: lit.span(),
+                literal: Some(literal),
+                offset: quote + 1,
+            },
+            _ => PatternSpan::new(lit.span()),
+        }
+    }
+
+    /// The span of the pattern starting `offset` bytes into this one.
+    pub fn skip(&self, offset: usize) -> Self {
+        PatternSpan { offset: self.offset + offset, ..self.clone() }
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// The span of bytes `range` of the pattern, or of the whole
+    /// pattern if the compiler can't point inside string literals.
+    pub fn subspan(&self, range: Range<usize>) -> Span {
+        self.literal.as_ref()
+            .and_then(|literal| literal.subspan((self.offset + range.start)..(self.offset + range.end)))
+            .unwrap_or(self.span)
+    }
+
+    /// An error about bytes `range` of `pattern`, quoting them so the
+    /// message makes sense even when it points at the whole pattern.
+    pub fn error(&self, pattern: &str, range: Range<usize>, message: &str) -> syn::Error {
+        let text = &pattern[range.clone()];
+        syn::Error::new(
+            self.subspan(range.clone()),
+            format!("{} (`{}` at {}..{} in the pattern)", message, text, range.start, range.end),
+        )
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::BTreeMap;
+
+use proc_macro2::Span;
+use syn::{Attribute, Expr, ExprLit, ExprRange, Fields, Ident, Lit, LitInt, RangeLimits, punctuated::Punctuated, spanned::Spanned};
+
+use crate::{bitpattern::{BitPattern, Literal, Range, RawMappingValue, Token}, diagnostic::PatternSpan};
+
+/// The order fields with `#[bits(N)]` are laid out in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    /// The first field holds the most significant bits, like the
+    /// first token of a pattern string.
+    MsbFirst,
+    LsbFirst,
+}
+
+/// Where a field goes: either the next `width` bits, or the bits
+/// `low..=high` of the encoded value.
+enum Placement {
+    Width(usize),
+    Position(usize, usize),
+}
+
+struct FieldBits {
+    placement: Placement,
+    signed: bool,
+}
+
+impl syn::parse::Parse for FieldBits {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let expr: Expr = input.parse()?;
+        let placement = match &expr {
+            Expr::Lit(ExprLit { lit: Lit::Int(width), .. }) => Placement::Width(width.base10_parse()?),
+            Expr::Range(_) => {
+                let (low, high) = parse_position(&expr)?;
+                Placement::Position(low, high)
+            },
+            _ => return Err(syn::Error::new(expr.span(), "Expected a width like #[bits(5)] or positions like #[bits(20..=24)]")),
+        };
+        let mut signed = false;
+        if input.parse::<Option<syn::Token![,]>>()?.is_some() {
+            let flag: syn::Ident = input.parse()?;
+            if flag != "signed" {
+                return Err(syn::Error::new(flag.span(), "Expected the only option after the bits to be signed"));
+            }
+            signed = true;
+        }
+        Ok(FieldBits { placement, signed })
+    }
+}
+
+/// Bits of a type or variant that always have the same value, given
+/// with `#[fixed(0..=6, 0b0110011)]`.
+struct Fixed {
+    low: usize,
+    high: usize,
+    value: LitInt,
+}
+
+impl syn::parse::Parse for Fixed {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let range: Expr = input.parse()?;
+        let (low, high) = parse_position(&range)?;
+        let _comma: syn::Token![,] = input.parse()?;
+        let value: LitInt = input.parse()?;
+        Ok(Fixed { low, high, value })
+    }
+}
+
+/// Parses an inclusive range of bit positions like `20..=24`.
+fn parse_position(expr: &Expr) -> syn::Result<(usize, usize)> {
+    fn bound(expr: &Option<Box<Expr>>) -> syn::Result<Option<usize>> {
+        match expr.as_deref() {
+            Some(Expr::Lit(ExprLit { lit: Lit::Int(value), .. })) => Ok(Some(value.base10_parse()?)),
+            _ => Ok(None),
+        }
+    }
+    if let Expr::Range(ExprRange { from, to, limits: RangeLimits::Closed(_), .. }) = expr {
+        if let (Some(low), Some(high)) = (bound(from)?, bound(to)?) {
+            if low <= high {
+                return Ok((low, high));
+            }
+        }
+    }
+    Err(syn::Error::new(expr.span(), "Expected an inclusive range of bit positions, lowest first, like 20..=24"))
+}
+
+/// Builds the pattern for a struct or variant described with field
+/// attributes instead of a pattern string, or returns None if it
+/// doesn't have any.
+///
+/// Fields either give their widths with `#[bits(N)]` and are laid
+/// out one after another, or give their positions with
+/// `#[bits(20..=24)]` alongside `#[fixed(...)]` bits. Sequential
+/// fields are most significant first unless the variant or the type
+/// has `#[bits(lsb_first)]`, and positions count from the least
+/// significant bit unless it has `#[bits(msb0)]`. A width given with
+/// `#[bits(msb0, 32)]` says where the encoded value ends when the
+/// positions don't reach its last bit.
+pub fn field_layout_pattern(type_attrs: &[Attribute], attrs: &[Attribute], fields: &Fields) -> syn::Result<Option<BitPattern>> {
+    let mut placed = vec![];
+    for (index, field) in fields.iter().enumerate() {
+        let mut bits = None;
+        for attr in &field.attrs {
+            if attr.path.is_ident("bits") {
+                bits = Some(attr.parse_args::<FieldBits>()?);
+            }
+        }
+        let name = match &field.ident {
+            Some(ident) => ident.to_string(),
+            None => index.to_string(),
+        };
+        placed.push((field.span(), name, bits));
+    }
+    let mut fixed = vec![];
+    for attr in attrs {
+        if attr.path.is_ident("fixed") {
+            fixed.push((attr.span(), attr.parse_args::<Fixed>()?));
+        }
+    }
+    if fixed.is_empty() && placed.iter().all(|(_, _, bits)| bits.is_none()) {
+        return Ok(None);
+    }
+    let positioned = !fixed.is_empty() || placed.iter().any(|(_, _, bits)| matches!(bits, Some(FieldBits { placement: Placement::Position(..), .. })));
+    let options = find_layout_options(attrs)?;
+    let type_options = find_layout_options(type_attrs)?;
+    let msb0 = options.msb0.or(type_options.msb0).unwrap_or(false);
+    let tokens = if positioned {
+        positioned_tokens(placed, fixed, msb0, options.width.as_ref().or(type_options.width.as_ref()))?
+    } else {
+        sequential_tokens(placed, options.order.or(type_options.order).unwrap_or(Order::MsbFirst))?
+    };
+    let span = fields.span();
+    let mut pattern = BitPattern::new(tokens, "", &PatternSpan::new(span));
+    if msb0 {
+        pattern.msb0 = Some(true);
+        for token in &pattern.tokens {
+            if let Token::Range(Range { name, len, .. }) = token {
+                pattern.field_widths.insert(pattern.resolve(name), *len);
+            }
+        }
+    }
+    Ok(Some(pattern))
+}
+
+fn sequential_tokens(placed: Vec<(Span, String, Option<FieldBits>)>, order: Order) -> syn::Result<Vec<Token>> {
+    let mut tokens = vec![];
+    for (span, name, bits) in placed {
+        let (width, signed) = match bits {
+            Some(FieldBits { placement: Placement::Width(width), signed }) => (width, signed),
+            _ => return Err(syn::Error::new(span, format!("Every field needs a width when they're laid out with #[bits(N)], but {} doesn't have one", name))),
+        };
+        if width == 0 {
+            return Err(syn::Error::new(span, format!("The width of {} has to be at least one bit", name)));
+        }
+        tokens.push(Token::Range(Range { name, start: 0, len: width, signed, offsets: None }));
+    }
+    if order == Order::LsbFirst {
+        tokens.reverse();
+    }
+    Ok(tokens)
+}
+
+/// The tokens for fields and fixed bits at the given positions, for
+/// layouts that are read from somewhere other than attributes.
+pub fn position_tokens(fields: Vec<(Span, String, usize, usize)>, fixed: Vec<(Span, usize, usize, LitInt)>) -> syn::Result<Vec<Token>> {
+    let placed = fields.into_iter()
+        .map(|(span, name, low, high)| (span, name, Some(FieldBits { placement: Placement::Position(low, high), signed: false })))
+        .collect();
+    let fixed = fixed.into_iter()
+        .map(|(span, low, high, value)| (span, Fixed { low, high, value }))
+        .collect();
+    positioned_tokens(placed, fixed, false, None)
+}
+
+/// The widths fields give with `#[bits(N)]`. In a pattern string
+/// numbered with `msb0`, they say how wide a field is when its ranges
+/// don't reach its last bit.
+pub fn declared_widths(fields: &Fields) -> syn::Result<BTreeMap<RawMappingValue, (Span, usize)>> {
+    let mut widths = BTreeMap::new();
+    for (index, field) in fields.iter().enumerate() {
+        for attr in &field.attrs {
+            if attr.path.is_ident("bits") {
+                if let FieldBits { placement: Placement::Width(width), .. } = attr.parse_args()? {
+                    let value = match &field.ident {
+                        Some(ident) => RawMappingValue::Name(ident.to_string()),
+                        None => RawMappingValue::Index(index),
+                    };
+                    widths.insert(value, (attr.span(), width));
+                }
+            }
+        }
+    }
+    Ok(widths)
+}
+
+/// The tokens for fields and fixed bits at the given positions. The
+/// encoded value is `width` bits wide if it's given, or ends at the
+/// highest position otherwise.
+fn positioned_tokens(placed: Vec<(Span, String, Option<FieldBits>)>, fixed: Vec<(Span, Fixed)>, msb0: bool, width: Option<&LitInt>) -> syn::Result<Vec<Token>> {
+    let mut segments = vec![];
+    for (span, name, bits) in placed {
+        match bits {
+            Some(FieldBits { placement: Placement::Position(low, high), signed }) => {
+                segments.push((low, high, span, Token::Range(Range { name, start: 0, len: high + 1 - low, signed, offsets: None })));
+            },
+            Some(FieldBits { placement: Placement::Width(_), .. }) => {
+                return Err(syn::Error::new(span, format!("{} gives a width, but the other bits are given by position. Use a range like #[bits(20..=24)] instead.", name)));
+            },
+            None => {},
+        }
+    }
+    for (span, Fixed { low, high, value }) in fixed {
+        let bits = high + 1 - low;
+        let parsed: u128 = value.base10_parse()?;
+        if bits > 128 || (bits < 128 && parsed >> bits != 0) {
+            return Err(syn::Error::new(value.span(), format!("{} doesn't fit in the {} bits {}..={}", value, bits, low, high)));
+        }
+        let bytes = parsed.to_le_bytes()[..((bits + 7) / 8)].to_vec();
+        segments.push((low, high, span, Token::Bits(Literal { bytes, bits })));
+    }
+    let highest = segments.iter().map(|(_, high, ..)| *high).max().unwrap_or(0);
+    let width = match width {
+        Some(width) => match width.base10_parse()? {
+            bits if bits > highest => bits,
+            bits => return Err(syn::Error::new(width.span(), format!("Bit {} is given, but the value is only {} bits wide", highest, bits))),
+        },
+        None => highest + 1,
+    };
+    if msb0 {
+        for (low, high, ..) in segments.iter_mut() {
+            (*low, *high) = (width - 1 - *high, width - 1 - *low);
+        }
+    }
+    segments.sort_by_key(|(low, ..)| *low);
+    for pair in segments.windows(2) {
+        let (_, high, _, _) = &pair[0];
+        let (low, _, span, _) = &pair[1];
+        if low <= high {
+            return Err(syn::Error::new(*span, format!("Bit {} is given more than once", low)));
+        }
+    }
+    let mut tokens = vec![];
+    let mut next = 0;
+    for (low, high, _, token) in segments {
+        if low > next {
+            tokens.push(Token::DontCare(low - next));
+        }
+        tokens.push(token);
+        next = high + 1;
+    }
+    if width > next {
+        tokens.push(Token::DontCare(width - next));
+    }
+    tokens.reverse();
+    Ok(tokens)
+}
+
+/// The options given on a type or variant with `#[bits(...)]`.
+#[derive(Default)]
+pub struct LayoutOptions {
+    /// The order of sequential fields.
+    pub order: Option<Order>,
+    /// Whether positions are numbered from the most significant bit
+    /// (`msb0`) or the least (`lsb0`).
+    pub msb0: Option<bool>,
+    /// The width of the encoded value, as in `#[bits(msb0, 32)]`, for
+    /// positions that don't reach its last bit.
+    pub width: Option<LitInt>,
+}
+
+enum LayoutOption {
+    Flag(Ident),
+    Width(LitInt),
+}
+
+impl syn::parse::Parse for LayoutOption {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        if input.peek(LitInt) {
+            Ok(LayoutOption::Width(input.parse()?))
+        } else {
+            Ok(LayoutOption::Flag(input.parse()?))
+        }
+    }
+}
+
+pub fn find_layout_options(attrs: &[Attribute]) -> syn::Result<LayoutOptions> {
+    let mut options = LayoutOptions::default();
+    for attr in attrs {
+        if attr.path.is_ident("bits") {
+            for option in attr.parse_args_with(Punctuated::<LayoutOption, syn::Token![,]>::parse_terminated)? {
+                match option {
+                    LayoutOption::Width(width) => options.width = Some(width),
+                    LayoutOption::Flag(flag) => match flag.to_string().as_str() {
+                        "msb_first" => options.order = Some(Order::MsbFirst),
+                        "lsb_first" => options.order = Some(Order::LsbFirst),
+                        "msb0" => options.msb0 = Some(true),
+                        "lsb0" => options.msb0 = Some(false),
+                        _ => return Err(syn::Error::new(flag.span(), "Expected msb_first, lsb_first, msb0, lsb0 or a width")),
+                    },
+                }
+            }
+        }
+    }
+    Ok(options)
+}
@@ -0,0 +1,52 @@
+use proc_macro2::TokenStream as TokenStreamInternal;
+use syn::{DeriveInput, spanned::Spanned, DataEnum};
+use quote::quote;
+
+use crate::{bitpattern::find_bit_pattern, common::{generics, generics_names}};
+
+const GENERIC_FAILURE: &str = r#"#[derive(FixedBitSize)] expects an attribute of the form #[bitpattern("11010[a:0-2]0110", a=foo)]"#;
+
+pub fn expand_fixed_bit_size(ast: &DeriveInput) -> syn::Result<TokenStreamInternal> {
+    let type_ident = &ast.ident;
+    let bits = match &ast.data {
+        syn::Data::Struct(data_struct) => {
+            if let Some(pattern) = find_bit_pattern(&ast.attrs, &ast.attrs, &data_struct.fields)? {
+                pattern.bits()
+            } else {
+                return Err(syn::Error::new(ast.ident.span(), GENERIC_FAILURE))
+            }
+        },
+        syn::Data::Enum(DataEnum { variants, .. }) => {
+            let mut bits: Option<(usize, &syn::Ident)> = None;
+            for variant in variants {
+                if let Some(pattern) = find_bit_pattern(&ast.attrs, &variant.attrs, &variant.fields)? {
+                    match bits {
+                        Some((expected, first)) if expected != pattern.bits() => {
+                            return Err(syn::Error::new(
+                                variant.ident.span(),
+                                format!("#[derive(FixedBitSize)] requires every variant to have the same size, but {} has {} bits and {} has {}.", variant.ident, pattern.bits(), first, expected)
+                            ))
+                        },
+                        Some(_) => {},
+                        None => bits = Some((pattern.bits(), &variant.ident)),
+                    }
+                } else {
+                    return Err(syn::Error::new(variant.span(), GENERIC_FAILURE))
+                }
+            }
+            match bits {
+                Some((bits, _)) => bits,
+                None => return Err(syn::Error::new(ast.ident.span(), "#[derive(FixedBitSize)] needs at least one variant.")),
+            }
+        },
+        syn::Data::Union(_) => return Err(syn::Error::new(ast.span(), "#[derive(FixedBitSize)] is not supported for untagged unions.")),
+    };
+    let generics = generics(ast);
+    let generics_names = generics_names(ast);
+    let where_clause = &ast.generics.where_clause;
+    Ok(quote! {
+        impl #generics ::decree::FixedBitSize for #type_ident #generics_names #where_clause {
+            const BITS: usize = #bits;
+        }
+    })
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+use syn::{Attribute, Ident, LitStr, Token, punctuated::Punctuated};
+
+use crate::{bitpattern::{BitPattern, parse_bit_pattern}, diagnostic::PatternSpan};
+
+/// A pattern defined with `#[bitformat(Name = "...")]`, with
+/// `{slot}` or `{slot:width}` placeholders that variants fill in.
+pub struct Format {
+    pub template: LitStr,
+}
+
+struct FormatDefinition {
+    name: Ident,
+    template: LitStr,
+}
+
+impl syn::parse::Parse for FormatDefinition {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let name: Ident = input.parse()?;
+        let _eq: Token![=] = input.parse()?;
+        let template: LitStr = input.parse()?;
+        Ok(FormatDefinition { name, template })
+    }
+}
+
+/// The formats defined on a type with `#[bitformat(...)]`.
+pub fn find_formats(attrs: &[Attribute]) -> syn::Result<HashMap<String, Format>> {
+    let mut formats = HashMap::new();
+    for attr in attrs {
+        if attr.path.is_ident("bitformat") {
+            let definitions = attr.parse_args_with(Punctuated::<FormatDefinition, Token![,]>::parse_terminated)?;
+            for definition in definitions {
+                let name = definition.name.to_string();
+                if formats.contains_key(&name) {
+                    return Err(syn::Error::new(definition.name.span(), format!("The format {} is already defined", name)));
+                }
+                formats.insert(name, Format { template: definition.template });
+            }
+        }
+    }
+    Ok(formats)
+}
+
+/// Fills the slots of the format called `name` with `values`, and
+/// parses the result. The template and each value are checked on
+/// their own first, so errors point at the string they're in.
+pub fn expand_format(formats: &HashMap<String, Format>, name: &Ident, values: &[(Ident, LitStr)]) -> syn::Result<BitPattern> {
+    let format = match formats.get(&name.to_string()) {
+        Some(format) => format,
+        None => return Err(syn::Error::new(name.span(), format!("There's no format named {}. Formats are defined on the type with #[bitformat({} = \"...\")].", name, name))),
+    };
+    let template = format.template.value();
+    let template_span = PatternSpan::literal(&format.template);
+    let mut used = vec![false; values.len()];
+    let mut pattern = String::new();
+    let mut rest = template.as_str();
+    while let Some(open) = rest.find('{') {
+        let offset = template.len() - rest.len();
+        parse_bit_pattern(&template_span.skip(offset), &rest[..open])?;
+        pattern.push_str(&rest[..open]);
+        let close = match rest[open..].find('}') {
+            Some(close) => open + close,
+            None => return Err(syn::Error::new(format.template.span(), "Expected a } to close the format slot")),
+        };
+        let slot = &rest[(open + 1)..close];
+        let (slot, width) = match slot.split_once(':') {
+            Some((slot, width)) => match width.parse::<usize>() {
+                Ok(width) => (slot, Some(width)),
+                Err(_) => return Err(syn::Error::new(format.template.span(), format!("Expected the width of slot {} to be a number", slot))),
+            },
+            None => (slot, None),
+        };
+        let index = match values.iter().position(|(ident, _)| ident == slot) {
+            Some(index) => index,
+            None => return Err(syn::Error::new(name.span(), format!("Missing a value for slot {} of format {}", slot, name))),
+        };
+        let (ident, value) = &values[index];
+        let bits = parse_bit_pattern(&PatternSpan::literal(value), &value.value())?.bits();
+        if let Some(width) = width {
+            if bits != width {
+                return Err(syn::Error::new(value.span(), format!("Slot {} of format {} is {} bits wide, but the value for it has {} bits", ident, name, width, bits)));
+            }
+        }
+        used[index] = true;
+        pattern.push_str(&value.value());
+        rest = &rest[(close + 1)..];
+    }
+    parse_bit_pattern(&template_span.skip(template.len() - rest.len()), rest)?;
+    pattern.push_str(rest);
+    for ((ident, _), used) in values.iter().zip(used) {
+        if !used {
+            return Err(syn::Error::new(ident.span(), format!("Format {} has no slot named {}", name, ident)));
+        }
+    }
+    parse_bit_pattern(&PatternSpan::new(format.template.span()), &pattern)
+}
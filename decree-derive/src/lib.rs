@@ -1,26 +1,72 @@
 use proc_macro::TokenStream as TokenStreamExternal;
 use syn::DeriveInput;
 use syn::parse_macro_input;
+use proc_macro2::TokenStream as TokenStreamInternal;
+use quote::quote;
 
+mod binding;
 mod bitpattern;
 mod bitsource;
 mod bitsink;
+mod bitdecode;
+mod bitlayout;
+mod bitmask;
+mod fixedbitsize;
 mod common;
+mod decisiontree;
+mod diagnostic;
+mod fieldlayout;
+mod format;
+mod specfile;
+mod transform;
 
-#[proc_macro_derive(BitSource, attributes(bitpattern))]
+#[proc_macro_derive(BitSource, attributes(bitpattern, bitformat, bits, fixed))]
 pub fn bit_source(input: TokenStreamExternal) -> TokenStreamExternal {
     let ast: DeriveInput = parse_macro_input!(input);
-    bitsource::expand_bit_source(&ast)
-        .unwrap_or_else(|err| err.to_compile_error())
-        .into()
+    expand(&ast, bitsource::expand_bit_source(&ast))
 }
 
-#[proc_macro_derive(BitSink, attributes(bitpattern))]
+#[proc_macro_derive(BitSink, attributes(bitpattern, bitformat, bits, fixed))]
 pub fn bit_sink(input: TokenStreamExternal) -> TokenStreamExternal {
     let ast: DeriveInput = parse_macro_input!(input);
-    bitsink::expand_bit_sink(&ast)
-        .unwrap_or_else(|err| err.to_compile_error())
-        .into()
+    expand(&ast, bitsink::expand_bit_sink(&ast))
 }
 
+#[proc_macro_derive(BitDecode, attributes(bitpattern, bitformat, bits, fixed))]
+pub fn bit_decode(input: TokenStreamExternal) -> TokenStreamExternal {
+    let ast: DeriveInput = parse_macro_input!(input);
+    expand(&ast, bitdecode::expand_bit_decode(&ast))
+}
+
+#[proc_macro_derive(FixedBitSize, attributes(bitpattern, bitformat, bits, fixed))]
+pub fn fixed_bit_size(input: TokenStreamExternal) -> TokenStreamExternal {
+    let ast: DeriveInput = parse_macro_input!(input);
+    expand(&ast, fixedbitsize::expand_fixed_bit_size(&ast))
+}
+
+#[proc_macro_derive(BitMask, attributes(bitpattern, bitformat, bits, fixed))]
+pub fn bit_mask(input: TokenStreamExternal) -> TokenStreamExternal {
+    let ast: DeriveInput = parse_macro_input!(input);
+    expand(&ast, bitmask::expand_bit_mask(&ast))
+}
+
+#[proc_macro_derive(BitLayout, attributes(bitpattern, bitformat, bits, fixed))]
+pub fn bit_layout(input: TokenStreamExternal) -> TokenStreamExternal {
+    let ast: DeriveInput = parse_macro_input!(input);
+    expand(&ast, bitlayout::expand_bit_layout(&ast))
+}
 
+/// The derive's output, along with the files its patterns are read
+/// from, or its error.
+fn expand(ast: &DeriveInput, expansion: syn::Result<TokenStreamInternal>) -> TokenStreamExternal {
+    match expansion {
+        Ok(tokens) => {
+            let dependencies = specfile::file_dependencies(ast);
+            quote! {
+                #tokens
+                #dependencies
+            }.into()
+        },
+        Err(err) => err.to_compile_error().into(),
+    }
+}
@@ -0,0 +1,141 @@
+use std::path::PathBuf;
+
+use proc_macro2::TokenStream as TokenStreamInternal;
+use syn::{Attribute, Data, DeriveInput, LitInt, LitStr};
+use quote::quote;
+
+use crate::{bitpattern::{BitPattern, Token}, fieldlayout::position_tokens};
+
+/// A pattern read from an opcodes file with `file = "..."`, in the
+/// style of riscv-opcodes. Each line names an entry, then lists its
+/// arguments and fixed bits, like `add rd rs1 rs2 31..25=0 14..12=0
+/// 6..2=0x0C 1..0=3`. The positions of the arguments come from an
+/// `arg_lut.csv` next to the file, or the one given with `args`.
+#[derive(Debug)]
+pub struct SpecEntry {
+    pub file: LitStr,
+    pub entry: Option<LitStr>,
+    pub args: Option<LitStr>,
+}
+
+impl SpecEntry {
+    pub fn tokens(&self) -> syn::Result<Vec<Token>> {
+        let entry = match &self.entry {
+            Some(entry) => entry,
+            None => return Err(syn::Error::new(self.file.span(), "Expected the name of an entry in the file, like entry = \"add\"")),
+        };
+        let path = manifest_path(&self.file);
+        let contents = read(&self.file, &path)?;
+        let line = contents.lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.split('#').next().unwrap_or("").trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('$'))
+            .find(|(_, line)| line.split_whitespace().next() == Some(entry.value().as_str()));
+        let (number, line) = match line {
+            Some(line) => line,
+            None => return Err(syn::Error::new(entry.span(), format!("There's no entry named {} in {}", entry.value(), self.file.value()))),
+        };
+        let args = match &self.args {
+            Some(args) => (args, manifest_path(args)),
+            None => (&self.file, path.with_file_name("arg_lut.csv")),
+        };
+        let mut arg_lut = None;
+        let mut fields = vec![];
+        let mut fixed = vec![];
+        for part in line.split_whitespace().skip(1) {
+            let error = |message: String| syn::Error::new(entry.span(), format!("{}, line {}: {}", self.file.value(), number, message));
+            match part.split_once('=') {
+                Some((bits, value)) => {
+                    let (high, low) = match bits.split_once("..") {
+                        Some((high, low)) => (parse_number(high), parse_number(low)),
+                        None => (parse_number(bits), parse_number(bits)),
+                    };
+                    let (high, low) = match (high, low) {
+                        (Some(high), Some(low)) if low <= high => (high as usize, low as usize),
+                        _ => return Err(error(format!("Expected bit positions like 31..25 or 12, highest first, but found {}", bits))),
+                    };
+                    let value = match parse_number(value) {
+                        Some(value) => LitInt::new(&value.to_string(), entry.span()),
+                        None => return Err(error(format!("Expected a number for bits {}, but found {}", bits, value))),
+                    };
+                    fixed.push((entry.span(), low, high, value));
+                },
+                None => {
+                    if arg_lut.is_none() {
+                        arg_lut = Some(read(args.0, &args.1)?);
+                    }
+                    let (high, low) = match find_arg(arg_lut.as_deref().unwrap_or(""), part) {
+                        Some(position) => position,
+                        None => return Err(error(format!("The argument {} isn't in {}", part, args.1.display()))),
+                    };
+                    fields.push((entry.span(), part.to_string(), low, high));
+                },
+            }
+        }
+        position_tokens(fields, fixed)
+    }
+}
+
+fn manifest_path(file: &LitStr) -> PathBuf {
+    let root = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    PathBuf::from(root).join(file.value())
+}
+
+fn read(file: &LitStr, path: &PathBuf) -> syn::Result<String> {
+    std::fs::read_to_string(path)
+        .map_err(|err| syn::Error::new(file.span(), format!("Couldn't read {}: {}", path.display(), err)))
+}
+
+/// A decimal, `0x` hex or `0b` binary number.
+fn parse_number(number: &str) -> Option<u128> {
+    if let Some(hex) = number.strip_prefix("0x") {
+        u128::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = number.strip_prefix("0b") {
+        u128::from_str_radix(binary, 2).ok()
+    } else {
+        number.parse().ok()
+    }
+}
+
+/// The highest and lowest bits of `arg` in an `arg_lut.csv`, where
+/// each line is like `"rd", 11, 7`.
+fn find_arg(arg_lut: &str, arg: &str) -> Option<(usize, usize)> {
+    arg_lut.lines().find_map(|line| {
+        let mut columns = line.split(',').map(|column| column.trim().trim_matches('"'));
+        if columns.next()? != arg {
+            return None;
+        }
+        let high = columns.next()?.parse().ok()?;
+        let low = columns.next()?.parse().ok()?;
+        if low <= high { Some((high, low)) } else { None }
+    })
+}
+
+/// `include_bytes!` for every file the type's patterns are read from,
+/// so the derive runs again when one of them changes.
+pub fn file_dependencies(ast: &DeriveInput) -> TokenStreamInternal {
+    let mut attrs: Vec<&Attribute> = ast.attrs.iter().collect();
+    if let Data::Enum(data_enum) = &ast.data {
+        attrs.extend(data_enum.variants.iter().flat_map(|variant| variant.attrs.iter()));
+    }
+    let mut paths = vec![];
+    for attr in attrs {
+        if attr.path.is_ident("bitpattern") {
+            if let Ok(BitPattern { spec: Some(spec), .. }) = attr.parse_args::<BitPattern>() {
+                paths.push(manifest_path(&spec.file).display().to_string());
+                let args = match &spec.args {
+                    Some(args) => manifest_path(args),
+                    None => manifest_path(&spec.file).with_file_name("arg_lut.csv"),
+                };
+                if args.exists() {
+                    paths.push(args.display().to_string());
+                }
+            }
+        }
+    }
+    paths.sort();
+    paths.dedup();
+    quote! {
+        #(const _: &[u8] = include_bytes!(#paths);)*
+    }
+}
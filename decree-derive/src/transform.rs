@@ -0,0 +1,136 @@
+use proc_macro2::TokenStream as TokenStreamInternal;
+use syn::{spanned::Spanned, BinOp, Expr, ExprBinary, ExprLit, ExprParen, Lit, LitInt};
+use quote::quote;
+
+/// A step in turning a field's value into the bits stored for it.
+/// Encoding applies the steps in order, and decoding undoes them in
+/// reverse.
+#[derive(Debug, Clone)]
+pub enum Transform {
+    /// `field >> n`. The low `n` bits of the field must be zero.
+    Shift(LitInt),
+    /// `field / n`. The field must be a multiple of `n`.
+    Scale(LitInt),
+    /// `field - n`.
+    Bias(LitInt),
+    /// `field + n`.
+    Offset(LitInt),
+}
+
+impl PartialEq for Transform {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Transform::Shift(a), Transform::Shift(b))
+            | (Transform::Scale(a), Transform::Scale(b))
+            | (Transform::Bias(a), Transform::Bias(b))
+            | (Transform::Offset(a), Transform::Offset(b)) => a.base10_digits() == b.base10_digits(),
+            _ => false,
+        }
+    }
+}
+
+/// Splits a mapping like `(offset - 4) >> 1` into the expression
+/// naming the field, and the transforms applied to it.
+pub fn split_transforms(expr: Expr) -> syn::Result<(Expr, Vec<Transform>)> {
+    match expr {
+        Expr::Paren(ExprParen { expr, .. }) => split_transforms(*expr),
+        Expr::Binary(ExprBinary { left, op, right, .. }) => {
+            let (inner, mut transforms) = split_transforms(*left)?;
+            let amount = match *right {
+                Expr::Lit(ExprLit { lit: Lit::Int(lit_int), .. }) => lit_int,
+                other => return Err(syn::Error::new(other.span(), "Expected an integer literal")),
+            };
+            let transform = match op {
+                BinOp::Shr(_) => Transform::Shift(amount),
+                BinOp::Div(_) => Transform::Scale(amount),
+                BinOp::Sub(_) => Transform::Bias(amount),
+                BinOp::Add(_) => Transform::Offset(amount),
+                _ => return Err(syn::Error::new(op.span(), "Expected one of >>, /, - or + in a field mapping")),
+            };
+            transforms.push(transform);
+            Ok((inner, transforms))
+        },
+        expr => Ok((expr, vec![])),
+    }
+}
+
+/// Code that turns `value` into the bits stored for the field, or
+/// returns an error if the value can't be stored exactly in the
+/// field's `bits` bits.
+pub fn encode_tokens(transforms: &[Transform], target: &str, field: &str, bits: usize, signed: bool) -> TokenStreamInternal {
+    let mut steps = vec![];
+    for transform in transforms {
+        steps.push(match transform {
+            Transform::Shift(amount) => quote! {
+                if (value >> #amount) << #amount != value {
+                    return Err(::decree::Error::misaligned(#target, #field, 1u128 << #amount));
+                }
+                let value = value >> #amount;
+            },
+            Transform::Scale(amount) => quote! {
+                if value % #amount != 0 {
+                    return Err(::decree::Error::misaligned(#target, #field, #amount as u128));
+                }
+                let value = value / #amount;
+            },
+            Transform::Bias(amount) => {
+                let message = format!("subtracting {} overflows", amount);
+                quote! {
+                    let value = match value.checked_sub(#amount) {
+                        Some(value) => value,
+                        None => return Err(::decree::Error::invalid_field_value(#field, #message)),
+                    };
+                }
+            },
+            Transform::Offset(amount) => {
+                let message = format!("adding {} overflows", amount);
+                quote! {
+                    let value = match value.checked_add(#amount) {
+                        Some(value) => value,
+                        None => return Err(::decree::Error::invalid_field_value(#field, #message)),
+                    };
+                }
+            },
+        });
+    }
+    let message = if signed {
+        format!("the mapped value doesn't fit in {} signed bits", bits)
+    } else {
+        format!("the mapped value doesn't fit in {} bits", bits)
+    };
+    let fits = if signed {
+        let shift = (bits - 1) as u32;
+        quote! {
+            let rest = value.checked_shr(#shift).unwrap_or(0);
+            if rest != 0 && rest != !0 {
+                return Err(::decree::Error::invalid_field_value(#field, #message));
+            }
+        }
+    } else {
+        let shift = bits as u32;
+        quote! {
+            if value.checked_shr(#shift).unwrap_or(0) != 0 {
+                return Err(::decree::Error::invalid_field_value(#field, #message));
+            }
+        }
+    };
+    quote! {
+        #(#steps)*
+        #fits
+    }
+}
+
+/// Code that turns the bits decoded into `target` back into the
+/// field's value.
+pub fn decode_tokens(transforms: &[Transform], target: &Expr) -> TokenStreamInternal {
+    let mut steps = vec![];
+    for transform in transforms.iter().rev() {
+        steps.push(match transform {
+            Transform::Shift(amount) => quote! { #target <<= #amount; },
+            Transform::Scale(amount) => quote! { #target = #target.wrapping_mul(#amount); },
+            Transform::Bias(amount) => quote! { #target = #target.wrapping_add(#amount); },
+            Transform::Offset(amount) => quote! { #target = #target.wrapping_sub(#amount); },
+        });
+    }
+    quote! { #(#steps)* }
+}
@@ -19,6 +19,31 @@ pub enum Error {
         output_start: usize,
         output_end: usize,
     },
+    #[error("None of the bit patterns for {target} match the {len} input bits.")]
+    NoMatchingPattern {
+        target: String,
+        len: usize,
+    },
+    #[error("The fields of {target} don't satisfy the requirement `{requirement}`.")]
+    RequirementFailed {
+        target: String,
+        requirement: String,
+    },
+    #[error("Field {field} of {target} has to be a multiple of {alignment} to be encoded.")]
+    Misaligned {
+        target: String,
+        field: String,
+        alignment: u128,
+    },
+    #[error("Invalid value for {field}: {message}")]
+    InvalidFieldValue {
+        field: String,
+        message: String,
+    },
+    #[error("The input matches {target}, but its reserved bits don't have the values they should, so it's unpredictable.")]
+    Unpredictable {
+        target: String,
+    },
     #[error("{message}")]
     WriteFailed {
         message: String,
@@ -61,6 +86,56 @@ impl Error {
         }
     }
 
+    pub fn no_matching_pattern(
+        target: impl core::fmt::Display,
+        len: usize,
+    ) -> Error {
+        Error::NoMatchingPattern {
+            target: target.to_string(),
+            len,
+        }
+    }
+
+    pub fn requirement_failed(
+        target: impl core::fmt::Display,
+        requirement: impl core::fmt::Display,
+    ) -> Error {
+        Error::RequirementFailed {
+            target: target.to_string(),
+            requirement: requirement.to_string(),
+        }
+    }
+
+    pub fn misaligned(
+        target: impl core::fmt::Display,
+        field: impl core::fmt::Display,
+        alignment: u128,
+    ) -> Error {
+        Error::Misaligned {
+            target: target.to_string(),
+            field: field.to_string(),
+            alignment,
+        }
+    }
+
+    pub fn invalid_field_value(
+        field: impl core::fmt::Display,
+        message: impl core::fmt::Display,
+    ) -> Error {
+        Error::InvalidFieldValue {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn unpredictable(
+        target: impl core::fmt::Display,
+    ) -> Error {
+        Error::Unpredictable {
+            target: target.to_string(),
+        }
+    }
+
     pub fn write_failed(
         self,
         message: impl core::fmt::Display,
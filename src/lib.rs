@@ -1,5 +1,5 @@
 mod error;
-pub use decree_derive::BitSource;
+pub use decree_derive::{BitSource, BitSink, BitDecode, BitLayout, BitMask, FixedBitSize};
 pub use error::Error;
 
 pub type Result<T> = core::result::Result<T, error::Error>;
@@ -55,6 +55,116 @@ pub trait BitSink {
     fn size(&self) -> Option<usize>;
 }
 
+pub trait BitDecode: Sized {
+    /// Decode a value from the `len` bits of `source` starting at
+    /// bit `start`. Only as many bits as the matching pattern needs
+    /// are consumed; `decode_with_length` tells how many that was.
+    #[inline]
+    fn decode(source: &impl BitSource, start: usize, len: usize) -> Result<Self> {
+        Self::decode_with_length(source, start, len).map(|(value, _)| value)
+    }
+
+    /// Like `decode`, but also returns the number of bits the value
+    /// was decoded from.
+    fn decode_with_length(source: &impl BitSource, start: usize, len: usize) -> Result<(Self, usize)>;
+
+    /// The number of bits the value at `start` would be decoded
+    /// from. Derived implementations work this out from the fixed
+    /// bits of the patterns, only decoding variants that have a
+    /// `require` to check.
+    #[inline]
+    fn decode_length(source: &impl BitSource, start: usize, len: usize) -> Result<usize> {
+        Self::decode_with_length(source, start, len).map(|(_, bits)| bits)
+    }
+
+    #[inline]
+    fn from_bits(source: &impl BitSource) -> Result<Self> {
+        Self::decode(source, 0, BitSource::size(source))
+    }
+}
+
+/// Decodes consecutive values from a byte buffer, read as little
+/// endian bits. Each value consumes as many bits as the pattern it
+/// matched, so values of different lengths can be mixed. Iteration
+/// stops after the first error.
+#[derive(Debug, Clone)]
+pub struct Decoder<'a, T> {
+    source: LittleEndian<&'a [u8]>,
+    pos: usize,
+    failed: bool,
+    marker: core::marker::PhantomData<T>,
+}
+
+impl<'a, T: BitDecode> Decoder<'a, T> {
+    pub fn new(bytes: &'a [u8]) -> Decoder<'a, T> {
+        Decoder {
+            source: LittleEndian::<&[u8]>::new(bytes),
+            pos: 0,
+            failed: false,
+            marker: core::marker::PhantomData,
+        }
+    }
+
+    /// The bit offset of the next value in the buffer.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+impl<'a, T: BitDecode> Iterator for Decoder<'a, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let size = BitSource::size(&self.source);
+        if self.failed || self.pos >= size {
+            return None;
+        }
+        match T::decode_with_length(&self.source, self.pos, size - self.pos) {
+            Ok((value, bits)) => {
+                self.pos += bits;
+                Some(Ok(value))
+            },
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            },
+        }
+    }
+}
+
+/// Types whose values always have the same number of bits, so their
+/// size is known at compile time.
+pub trait FixedBitSize {
+    const BITS: usize;
+}
+
+/// Finds the `FixedBitSize` of `T` if it has one, so the derives can
+/// check that a pattern's ranges fit in a field at compile time.
+/// `BitSizeProbe::<T>::BITS` is `Some` if `T: FixedBitSize`, and
+/// otherwise falls back to `None` from `UnknownBitSize`. In generic
+/// code that's decided from the bounds, so a generic `T` that isn't
+/// bounded by `FixedBitSize` always gets `None`. The derives require
+/// that bound on generic fields so they can be checked.
+#[doc(hidden)]
+pub struct BitSizeProbe<T: ?Sized>(core::marker::PhantomData<T>);
+
+#[doc(hidden)]
+pub trait UnknownBitSize {
+    const BITS: Option<usize> = None;
+}
+
+impl<T: ?Sized> UnknownBitSize for BitSizeProbe<T> {}
+
+impl<T: FixedBitSize + ?Sized> BitSizeProbe<T> {
+    pub const BITS: Option<usize> = Some(T::BITS);
+}
+
+/// Types that can copy one of their bits into every bit above it,
+/// for decoding two's complement values narrower than the type.
+pub trait SignExtend {
+    fn sign_extend(&mut self, bit: usize);
+}
+
 #[derive(Debug, Clone)]
 pub struct LittleEndian<T> {
     bytes: T,
@@ -75,12 +185,7 @@ pub struct DisplayBits<'a, T: BitSource> {
 impl<'a, T: BitSource> core::fmt::Display for DisplayBits<'a, T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let num_bits = self.source.size();
-        let num_bytes = num_bits / 8;
-        let num_bytes = if num_bits % 8 != 0 {
-            num_bytes + 1
-        } else {
-            num_bytes
-        };
+        let num_bytes = num_bits.div_ceil(8);
         let mut bytes = vec![0u8; num_bytes];
         {
             let mut sink = LittleEndian::<Vec<u8>>::with_bits(&mut bytes, num_bits).unwrap();
@@ -174,10 +279,10 @@ impl<T: AsRef<[u8]> + AsMut<[u8]>> BitSink for LittleEndian<T> {
             len,
             size
         )?;
-        let mut bytes = self.bytes.as_mut();
+        let bytes = self.bytes.as_mut();
         match endianness {
-            Endianness::BIG => write_bits(source, start, len, pos, &mut bytes, next_chunk_be, write_chunk_le_helper),
-            Endianness::LITTLE => write_bits(source, start, len, pos, &mut bytes, next_chunk_le, write_chunk_le_helper),
+            Endianness::BIG => write_bits(source, start, len, pos, bytes, next_chunk_be, write_chunk_le_helper),
+            Endianness::LITTLE => write_bits(source, start, len, pos, bytes, next_chunk_le, write_chunk_le_helper),
         }
     }
 
@@ -252,10 +357,10 @@ impl<T: AsRef<[u8]> + AsMut<[u8]>> BitSink for BigEndian<T> {
             len,
             size
         )?;
-        let mut bytes = self.bytes.as_mut();
+        let bytes = self.bytes.as_mut();
         match endianness {
-            Endianness::BIG => write_bits(source, start, len, pos, &mut bytes, next_chunk_be, write_chunk_be_helper),
-            Endianness::LITTLE => write_bits(source, start, len, pos, &mut bytes, next_chunk_le, write_chunk_be_helper),
+            Endianness::BIG => write_bits(source, start, len, pos, bytes, next_chunk_be, write_chunk_be_helper),
+            Endianness::LITTLE => write_bits(source, start, len, pos, bytes, next_chunk_le, write_chunk_be_helper),
         }
     }
 
@@ -285,15 +390,24 @@ pub fn check_input_range(
     Ok(())
 }
 
+/// Returns true if the bits of `bytes` selected by `mask` are equal
+/// to `value`. All three are little endian.
+#[inline]
+pub fn matches_pattern(bytes: &[u8], mask: &[u8], value: &[u8]) -> bool {
+    bytes.iter()
+        .zip(mask.iter().zip(value.iter()))
+        .all(|(byte, (mask, value))| byte & mask == *value)
+}
+
 #[inline]
-fn check_output_range(
+pub fn check_output_range(
     sink: impl core::fmt::Display,
     start: usize,
     len: usize,
     output_len: Option<usize>,
 ) -> Result<()> {
     if let Some(output_len) = output_len {
-        let space_to_write = output_len - start;
+        let space_to_write = output_len.saturating_sub(start);
         if space_to_write < len {
             return Err(Error::output_bits_out_of_range(
                 sink, len, start, output_len,
@@ -798,6 +912,96 @@ impl BitSink for i128 {
     }
 }
 
+impl SignExtend for i8 {
+    #[inline]
+    fn sign_extend(&mut self, bit: usize) {
+        if bit < 7 {
+            let shift = 7 - bit;
+            *self = (*self << shift) >> shift;
+        }
+    }
+}
+
+impl SignExtend for i16 {
+    #[inline]
+    fn sign_extend(&mut self, bit: usize) {
+        if bit < 15 {
+            let shift = 15 - bit;
+            *self = (*self << shift) >> shift;
+        }
+    }
+}
+
+impl SignExtend for i32 {
+    #[inline]
+    fn sign_extend(&mut self, bit: usize) {
+        if bit < 31 {
+            let shift = 31 - bit;
+            *self = (*self << shift) >> shift;
+        }
+    }
+}
+
+impl SignExtend for i64 {
+    #[inline]
+    fn sign_extend(&mut self, bit: usize) {
+        if bit < 63 {
+            let shift = 63 - bit;
+            *self = (*self << shift) >> shift;
+        }
+    }
+}
+
+impl SignExtend for i128 {
+    #[inline]
+    fn sign_extend(&mut self, bit: usize) {
+        if bit < 127 {
+            let shift = 127 - bit;
+            *self = (*self << shift) >> shift;
+        }
+    }
+}
+
+impl FixedBitSize for u8 {
+    const BITS: usize = 8;
+}
+
+impl FixedBitSize for u16 {
+    const BITS: usize = 16;
+}
+
+impl FixedBitSize for u32 {
+    const BITS: usize = 32;
+}
+
+impl FixedBitSize for u64 {
+    const BITS: usize = 64;
+}
+
+impl FixedBitSize for u128 {
+    const BITS: usize = 128;
+}
+
+impl FixedBitSize for i8 {
+    const BITS: usize = 8;
+}
+
+impl FixedBitSize for i16 {
+    const BITS: usize = 16;
+}
+
+impl FixedBitSize for i32 {
+    const BITS: usize = 32;
+}
+
+impl FixedBitSize for i64 {
+    const BITS: usize = 64;
+}
+
+impl FixedBitSize for i128 {
+    const BITS: usize = 128;
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct BitChunk {
     byte: u8,
@@ -840,7 +1044,7 @@ impl BitChunk {
 
 #[inline]
 fn next_chunk_be(bytes: &[u8], start: usize, len: usize) -> BitChunk {
-    let chunk = if len == 0 {
+    if len == 0 {
         BitChunk { byte: 0, bits: 0 }
     } else {
         let byte = bytes.len() - 1 - (start / 8);
@@ -852,13 +1056,12 @@ fn next_chunk_be(bytes: &[u8], start: usize, len: usize) -> BitChunk {
             byte: value,
             bits: num_bits.try_into().unwrap(),
         }
-    };
-    chunk
+    }
 }
 
 #[inline]
 fn next_chunk_le(bytes: &[u8], start: usize, len: usize) -> BitChunk {
-    let chunk = if len == 0 {
+    if len == 0 {
         BitChunk { byte: 0, bits: 0 }
     } else {
         let byte = start / 8;
@@ -870,8 +1073,7 @@ fn next_chunk_le(bytes: &[u8], start: usize, len: usize) -> BitChunk {
             byte: value,
             bits: num_bits.try_into().unwrap(),
         }
-    };
-    chunk
+    }
 }
 
 #[inline]
@@ -885,11 +1087,10 @@ fn write_chunk_le_helper(chunk: BitChunk, bytes: &mut [u8], start: usize) -> Bit
     let mask = mask(num_bits);
     let value = (bytes[byte] & !(mask << bit)) | ((chunk.byte() & mask) << bit);
     bytes[byte] = value;
-    let chunk = BitChunk {
-        byte: chunk.byte() >> num_bits,
+    BitChunk {
+        byte: chunk.byte().checked_shr(num_bits as u32).unwrap_or(0),
         bits: (chunk.bits() - num_bits).try_into().unwrap(),
-    };
-    chunk
+    }
 }
 
 #[inline]
@@ -903,11 +1104,10 @@ fn write_chunk_be_helper(chunk: BitChunk, bytes: &mut [u8], start: usize) -> Bit
     let mask = mask(num_bits);
     let value = (bytes[byte] & !(mask << bit)) | ((chunk.byte() & mask) << bit);
     bytes[byte] = value;
-    let chunk = BitChunk {
-        byte: chunk.byte() >> num_bits,
+    BitChunk {
+        byte: chunk.byte().checked_shr(num_bits as u32).unwrap_or(0),
         bits: (chunk.bits() - num_bits).try_into().unwrap(),
-    };
-    chunk
+    }
 }
 
 #[inline]
@@ -1010,6 +1210,48 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sign_extend() {
+        let mut value = 0b0000_1000i8;
+        value.sign_extend(3);
+        assert_eq!(value, -8);
+
+        let mut value = 0b0111_1111_1111i32;
+        value.sign_extend(11);
+        assert_eq!(value, 2047);
+
+        let mut value = 0b1000_0000_0000i64;
+        value.sign_extend(11);
+        assert_eq!(value, -2048);
+
+        let mut value = -1i16;
+        value.sign_extend(15);
+        assert_eq!(value, -1);
+    }
+
+    #[test]
+    fn test_bit_size_probe() {
+        struct Unsized;
+        assert_eq!(BitSizeProbe::<u8>::BITS, Some(8));
+        assert_eq!(BitSizeProbe::<i128>::BITS, Some(128));
+        assert_eq!(BitSizeProbe::<Unsized>::BITS, None);
+    }
+
+    #[test]
+    fn test_write_whole_bytes() -> Result<()> {
+        let mut output_bytes = [0u8; 2];
+        let mut output = LittleEndian::<&[u8]>::new(&mut output_bytes);
+        0b10100101_11000011u16.write(&mut output, 0, 16, 0)?;
+        compare_arrays(&output_bytes, &[0b11000011, 0b10100101]);
+
+        let mut output_bytes = [0u8; 2];
+        let mut output = BigEndian::<&[u8]>::new(&mut output_bytes);
+        0b10100101_11000011u16.write(&mut output, 0, 16, 0)?;
+        compare_arrays(&output_bytes, &[0b10100101, 0b11000011]);
+
+        Ok(())
+    }
+
     #[test]
     fn test_big_endian() -> Result<()> {
         let mut output_bytes = [0u8; 3];
@@ -1430,7 +1672,7 @@ mod tests {
 
     fn compare_arrays(found: &[u8], expected: &[u8]) {
         if found != expected {
-            panic!("Expected {}, found {}", print_array(&expected), print_array(&found));
+            panic!("Expected {}, found {}", print_array(expected), print_array(found));
         }
     }
 
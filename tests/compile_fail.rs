@@ -0,0 +1,7 @@
+// trybuild builds the cases from target/tests/trybuild/decree, so the
+// spec file cases reach tests/isa through ../../../../.
+#[test]
+fn test_compile_fail() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
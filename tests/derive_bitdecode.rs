@@ -0,0 +1,873 @@
+use decree::{BitSource, BitDecode, BitLayout, BitMask, Decoder, Error, FixedBitSize, LittleEndian, Result};
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode, FixedBitSize)]
+enum Op {
+    #[bitpattern("0000[rd:0-3][imm:0-7]")]
+    Load { rd: u8, imm: u8 },
+    #[bitpattern("0001[0:0-3][1:0-3][2:0-3]")]
+    Add(u8, u8, u8),
+    #[bitpattern("0010[x:0-3]0000[y:0-3]", x = 0, y = 1)]
+    Mov(u8, u8),
+    #[bitpattern("1111111111111111")]
+    Halt,
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode)]
+enum Instr {
+    #[bitpattern("111[x:1]111[x:3]11[x:4]11[y:4-9]1[z:12]", z = y)]
+    Foo { x: i8, y: u64 },
+    #[bitpattern("11101110011001101001110")]
+    Baz,
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode)]
+#[bitpattern("1010[a:0-3]11[b:0-5]")]
+struct Header {
+    a: u8,
+    b: u16,
+}
+
+#[test]
+fn test_decode_op() -> Result<()> {
+    assert_eq!(Op::from_bits(&0b0000_0101_1100_1010u16)?, Op::Load { rd: 0b0101, imm: 0b11001010 });
+    assert_eq!(Op::from_bits(&0b0001_0011_0010_0001u16)?, Op::Add(3, 2, 1));
+    assert_eq!(Op::from_bits(&0b0010_1001_0000_0110u16)?, Op::Mov(9, 6));
+    assert_eq!(Op::from_bits(&0xFFFFu16)?, Op::Halt);
+
+    Ok(())
+}
+
+#[test]
+fn test_decode_op_roundtrip() -> Result<()> {
+    for op in [Op::Load { rd: 7, imm: 0xA5 }, Op::Add(15, 0, 8), Op::Mov(1, 14), Op::Halt] {
+        let encoded: u16 = op.bits_into()?;
+        assert_eq!(Op::from_bits(&encoded)?, op);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_decode_op_no_match() {
+    let result = Op::from_bits(&0b0011_0000_0000_0000u16);
+    assert_eq!(result, Err(Error::no_matching_pattern("Op", 16)));
+
+    let result = Op::from_bits(&0b0010_1001_0100_0110u16);
+    assert_eq!(result, Err(Error::no_matching_pattern("Op", 16)));
+}
+
+#[test]
+fn test_decode_op_range() -> Result<()> {
+    let word = 0b00010011001000010000000u32;
+    assert_eq!(Op::decode(&word, 7, 16)?, Op::Add(3, 2, 1));
+
+    Ok(())
+}
+
+#[test]
+fn test_decode_instr() -> Result<()> {
+    assert_eq!(Instr::from_bits(&0b111011101101100000010u32)?, Instr::Foo { x: 0, y: 0 });
+    assert_eq!(Instr::from_bits(&0b11101110011001101001110u32)?, Instr::Baz);
+
+    let instr = Instr::Foo { x: 0b11010, y: 0b1001111110000 };
+    let encoded: u32 = instr.bits_into()?;
+    assert_eq!(Instr::from_bits(&encoded)?, instr);
+
+    Ok(())
+}
+
+#[test]
+fn test_decode_struct() -> Result<()> {
+    assert_eq!(Header::from_bits(&0b1010011011101101u16)?, Header { a: 0b0110, b: 0b101101 });
+
+    let result = Header::from_bits(&0b1011011011101101u16);
+    assert_eq!(result, Err(Error::no_matching_pattern("Header", 16)));
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode)]
+enum Alu {
+    #[bitpattern("0000[a:0-3]")] Add { a: u8 },
+    #[bitpattern("0001[a:0-3]")] Sub { a: u8 },
+    #[bitpattern("0010[a:0-3]")] And { a: u8 },
+    #[bitpattern("0011[a:0-3]")] Or { a: u8 },
+    #[bitpattern("0100[a:0-3]")] Xor { a: u8 },
+    #[bitpattern("0101[a:0-3]")] Shl { a: u8 },
+    #[bitpattern("0110[a:0-3]")] Shr { a: u8 },
+    #[bitpattern("0111[a:0-3]")] Rol { a: u8 },
+    #[bitpattern("1000[a:0-3]")] Ror { a: u8 },
+    #[bitpattern("1001[a:0-3]")] Not { a: u8 },
+    #[bitpattern("1010[a:0-3]")] Neg { a: u8 },
+    #[bitpattern("1011[a:0-3]")] Inc { a: u8 },
+    #[bitpattern("1100[a:0-3]")] Dec { a: u8 },
+    #[bitpattern("1101[a:0-3]")] Cmp { a: u8 },
+    #[bitpattern("1110[a:0-3]")] Tst { a: u8 },
+    #[bitpattern("1111[a:0-3]")] Mov { a: u8 },
+}
+
+#[test]
+fn test_decode_alu() -> Result<()> {
+    let ops = [
+        Alu::Add { a: 0 }, Alu::Sub { a: 1 }, Alu::And { a: 2 }, Alu::Or { a: 3 },
+        Alu::Xor { a: 4 }, Alu::Shl { a: 5 }, Alu::Shr { a: 6 }, Alu::Rol { a: 7 },
+        Alu::Ror { a: 8 }, Alu::Not { a: 9 }, Alu::Neg { a: 10 }, Alu::Inc { a: 11 },
+        Alu::Dec { a: 12 }, Alu::Cmp { a: 13 }, Alu::Tst { a: 14 }, Alu::Mov { a: 15 },
+    ];
+    for op in ops {
+        let encoded: u8 = op.bits_into()?;
+        assert_eq!(Alu::from_bits(&encoded)?, op);
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode)]
+enum Mixed {
+    #[bitpattern("1111[x:0-3]", priority = 1)]
+    Short { x: u8 },
+    #[bitpattern("11111111", priority = 2)]
+    Nop,
+    #[bitpattern("10[x:0-5]", priority = 1)]
+    Imm { x: u8 },
+    #[bitpattern("0[x:0-5]1[y:0-7]")]
+    Long { x: u8, y: u8 },
+}
+
+#[test]
+fn test_decode_mixed() -> Result<()> {
+    assert_eq!(Mixed::from_bits(&0b11111111u8)?, Mixed::Nop);
+    assert_eq!(Mixed::from_bits(&0b11110110u8)?, Mixed::Short { x: 0b0110 });
+    assert_eq!(Mixed::from_bits(&0b10101010u8)?, Mixed::Imm { x: 0b101010 });
+    assert_eq!(Mixed::from_bits(&0b0101010100000001u16)?, Mixed::Long { x: 0b101010, y: 0b00000001 });
+    assert_eq!(Mixed::from_bits(&0b0101010110000001u16)?, Mixed::Imm { x: 0b000001 });
+    assert_eq!(Mixed::from_bits(&0b11000000u8), Err(Error::no_matching_pattern("Mixed", 8)));
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode)]
+enum Imm {
+    #[bitpattern("[imm:0-11:s][rd:0-4]0010011")]
+    Addi { imm: i32, rd: u8 },
+    #[bitpattern("[imm:0-11][rd:0-4]0110011")]
+    Unsigned { imm: i32, rd: u8 },
+    #[bitpattern("[imm:7:s]1111111")]
+    Byte { imm: i8 },
+}
+
+#[test]
+fn test_decode_signed() -> Result<()> {
+    let instr = Imm::Addi { imm: -1, rd: 5 };
+    let encoded: u32 = instr.bits_into()?;
+    assert_eq!(encoded, 0b111111111111001010010011u32);
+    assert_eq!(Imm::from_bits(&encoded)?, instr);
+
+    for imm in [-2048, -1000, -2, 0, 1, 2047] {
+        let instr = Imm::Addi { imm, rd: 31 };
+        let encoded: u32 = instr.bits_into()?;
+        assert_eq!(Imm::from_bits(&encoded)?, instr);
+    }
+
+    assert_eq!(Imm::from_bits(&0b111111111111001010110011u32)?, Imm::Unsigned { imm: 4095, rd: 5 });
+    assert_eq!(Imm::from_bits(&0b11111111u8)?, Imm::Byte { imm: -128 });
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode, BitLayout, FixedBitSize)]
+enum Jump {
+    #[bitpattern("[imm:12:s][imm:5-10][rs2:0-4][rs1:0-4]000[imm:1-4][imm:11]1100011")]
+    Beq { imm: i32, rs1: u8, rs2: u8 },
+    #[bitpattern("[0:12:s][0:5-10][2:0-4][1:0-4]001[0:1-4][0:11]1100011")]
+    Bne(i32, u8, u8),
+    #[bitpattern("[imm:20:s][imm:1-10][imm:11][imm:12-19][rd:0-4]1101111", imm = offset)]
+    Jal { offset: i32, rd: u8 },
+}
+
+#[test]
+fn test_decode_scattered() -> Result<()> {
+    assert_eq!(Jump::from_bits(&0xFE000EE3u32)?, Jump::Beq { imm: -4, rs1: 0, rs2: 0 });
+    assert_eq!(Jump::from_bits(&0xFF9FF06Fu32)?, Jump::Jal { offset: -8, rd: 0 });
+
+    for imm in [-4096, -2050, -2, 0, 2, 2046, 4094] {
+        let instr = Jump::Beq { imm, rs1: 3, rs2: 17 };
+        let encoded: u32 = instr.bits_into()?;
+        assert_eq!(Jump::from_bits(&encoded)?, instr);
+
+        let instr = Jump::Bne(imm, 30, 1);
+        let encoded: u32 = instr.bits_into()?;
+        assert_eq!(Jump::from_bits(&encoded)?, instr);
+    }
+
+    for offset in [-1048576, -4098, -2, 0, 2, 4094, 1048574] {
+        let instr = Jump::Jal { offset, rd: 1 };
+        let encoded: u32 = instr.bits_into()?;
+        assert_eq!(Jump::from_bits(&encoded)?, instr);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_decode_scattered_uncovered_bits() -> Result<()> {
+    let encoded: u32 = Jump::Beq { imm: 1, rs1: 0, rs2: 0 }.bits_into()?;
+    assert_eq!(Jump::from_bits(&encoded)?, Jump::Beq { imm: 0, rs1: 0, rs2: 0 });
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode, BitLayout)]
+enum Fence {
+    #[bitpattern("xxxx[pred:0-3][succ:0-3]?????000?????0001111")]
+    Fence { pred: u8, succ: u8 },
+    #[bitpattern("xxxxxxxxxxxxxxxxx001xxxxx0001111", dont_care = 1)]
+    FenceI,
+}
+
+#[test]
+fn test_decode_dont_care() -> Result<()> {
+    let encoded: u32 = Fence::Fence { pred: 0b0011, succ: 0b1100 }.bits_into()?;
+    assert_eq!(encoded, 0b00000011110000000000000000001111u32);
+    assert_eq!(Fence::from_bits(&encoded)?, Fence::Fence { pred: 0b0011, succ: 0b1100 });
+    assert_eq!(Fence::from_bits(&0b10100011110010101000101010001111u32)?, Fence::Fence { pred: 0b0011, succ: 0b1100 });
+
+    let encoded: u32 = Fence::FenceI.bits_into()?;
+    assert_eq!(encoded, 0b11111111111111111001111110001111u32);
+    assert_eq!(Fence::from_bits(&encoded)?, Fence::FenceI);
+    assert_eq!(Fence::from_bits(&0b00000000000000000001000000001111u32)?, Fence::FenceI);
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode)]
+enum Compressed {
+    #[bitpattern("000[nzuimm:4-5][nzuimm:6-9][nzuimm:2][nzuimm:3][rd:0-2]00", require = nzuimm != 0)]
+    Addi4spn { rd: u8, nzuimm: u16 },
+    #[bitpattern("011[imm:9:s]00010[imm:4][imm:6][imm:7-8][imm:5]01", require = imm != 0, priority = 1)]
+    Addi16sp { imm: i16 },
+    #[bitpattern("011[imm:17:s][rd:0-4][imm:12-16]01", require = rd != 0 && rd != 2, require = imm != 0)]
+    Lui { rd: u8, imm: i32 },
+}
+
+#[test]
+fn test_decode_require() -> Result<()> {
+    assert_eq!(Compressed::from_bits(&0x0040u16)?, Compressed::Addi4spn { rd: 0, nzuimm: 4 });
+    assert_eq!(Compressed::from_bits(&0x0000u16), Err(Error::no_matching_pattern("Compressed", 16)));
+    assert_eq!(Compressed::from_bits(&0x7101u16)?, Compressed::Addi16sp { imm: -512 });
+    assert_eq!(Compressed::from_bits(&0x6101u16), Err(Error::no_matching_pattern("Compressed", 16)));
+    assert_eq!(Compressed::from_bits(&0x6505u16)?, Compressed::Lui { rd: 10, imm: 4096 });
+    assert_eq!(Compressed::from_bits(&0x6005u16), Err(Error::no_matching_pattern("Compressed", 16)));
+    assert_eq!(Compressed::from_bits(&0x6501u16), Err(Error::no_matching_pattern("Compressed", 16)));
+
+    for instr in [
+        Compressed::Addi4spn { rd: 7, nzuimm: 1020 },
+        Compressed::Addi16sp { imm: 496 },
+        Compressed::Lui { rd: 1, imm: -131072 },
+    ] {
+        let encoded: u16 = instr.bits_into()?;
+        assert_eq!(Compressed::from_bits(&encoded)?, instr);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_encode_require() {
+    let result: Result<u16> = Compressed::Lui { rd: 2, imm: 4096 }.bits_into();
+    match result {
+        Err(Error::WriteFailed { cause, .. }) => {
+            assert_eq!(*cause, Error::requirement_failed("Compressed::Lui", "rd != 0 && rd != 2 && imm != 0"));
+        },
+        other => panic!("Expected a failed requirement, got {:?}", other),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode)]
+enum Branch {
+    #[bitpattern("[off:11:s][off:4-9][rs2:0-4][rs1:0-4]000[off:0-3][off:10]1100011", off = offset >> 1)]
+    Beq { offset: i32, rs1: u8, rs2: u8 },
+    #[bitpattern("[offset:19:s][offset:0-9][offset:10][offset:11-18][rd:0-4]1101111", offset = offset >> 1)]
+    Jal { offset: i32, rd: u8 },
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode)]
+#[bitpattern("[n:0-3][s:0-3]", n = len - 1, s = stride / 4)]
+struct Burst {
+    len: u8,
+    stride: u16,
+}
+
+#[test]
+fn test_decode_shifted() -> Result<()> {
+    for offset in [-4096, -2050, -2, 0, 2, 2046, 4094] {
+        let encoded: u32 = Branch::Beq { offset, rs1: 3, rs2: 17 }.bits_into()?;
+        let expected: u32 = Jump::Beq { imm: offset, rs1: 3, rs2: 17 }.bits_into()?;
+        assert_eq!(encoded, expected);
+        assert_eq!(Branch::from_bits(&encoded)?, Branch::Beq { offset, rs1: 3, rs2: 17 });
+    }
+
+    for offset in [-1048576, -2, 0, 2, 1048574] {
+        let encoded: u32 = Branch::Jal { offset, rd: 1 }.bits_into()?;
+        let expected: u32 = Jump::Jal { offset, rd: 1 }.bits_into()?;
+        assert_eq!(encoded, expected);
+        assert_eq!(Branch::from_bits(&encoded)?, Branch::Jal { offset, rd: 1 });
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_decode_scaled_and_biased() -> Result<()> {
+    let encoded: u8 = Burst { len: 1, stride: 0 }.bits_into()?;
+    assert_eq!(encoded, 0x00);
+    let encoded: u8 = Burst { len: 16, stride: 60 }.bits_into()?;
+    assert_eq!(encoded, 0xFF);
+    let encoded: u8 = Burst { len: 3, stride: 8 }.bits_into()?;
+    assert_eq!(encoded, 0x22);
+    assert_eq!(Burst::from_bits(&0x22u8)?, Burst { len: 3, stride: 8 });
+
+    Ok(())
+}
+
+#[test]
+fn test_encode_misaligned() {
+    let result: Result<u32> = Branch::Beq { offset: 3, rs1: 0, rs2: 0 }.bits_into();
+    match result {
+        Err(Error::WriteFailed { cause, .. }) => {
+            assert_eq!(*cause, Error::misaligned("Branch::Beq", "offset", 2));
+        },
+        other => panic!("Expected a misaligned field, got {:?}", other),
+    }
+
+    let result: Result<u8> = Burst { len: 1, stride: 6 }.bits_into();
+    match result {
+        Err(Error::WriteFailed { cause, .. }) => {
+            assert_eq!(*cause, Error::misaligned("Burst", "stride", 4));
+        },
+        other => panic!("Expected a misaligned field, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_encode_out_of_range() {
+    let cases: [(Result<u8>, Error); 3] = [
+        (Burst { len: 0, stride: 0 }.bits_into(), Error::invalid_field_value("len", "subtracting 1 overflows")),
+        (Burst { len: 17, stride: 0 }.bits_into(), Error::invalid_field_value("len", "the mapped value doesn't fit in 4 bits")),
+        (Burst { len: 1, stride: 64 }.bits_into(), Error::invalid_field_value("stride", "the mapped value doesn't fit in 4 bits")),
+    ];
+    for (result, expected) in cases {
+        match result {
+            Err(Error::WriteFailed { cause, .. }) => assert_eq!(*cause, expected),
+            other => panic!("Expected {:?}, got {:?}", expected, other),
+        }
+    }
+
+    for offset in [-4098, 4096] {
+        let result: Result<u32> = Branch::Beq { offset, rs1: 0, rs2: 0 }.bits_into();
+        match result {
+            Err(Error::WriteFailed { cause, .. }) => {
+                assert_eq!(*cause, Error::invalid_field_value("offset", "the mapped value doesn't fit in 12 signed bits"));
+            },
+            other => panic!("Expected an out of range offset, got {:?}", other),
+        }
+    }
+}
+
+mod rotated_imm {
+    use decree::{Error, Result};
+
+    pub fn encode(value: &u32) -> Result<u16> {
+        for rotation in 0..16 {
+            let imm8 = value.rotate_left(2 * rotation);
+            if imm8 <= 0xFF {
+                return Ok(((rotation as u16) << 8) | imm8 as u16);
+            }
+        }
+        Err(Error::invalid_field_value("imm", format!("{:#x} isn't an 8-bit value rotated by an even amount", value)))
+    }
+
+    pub fn decode(bits: u16) -> Result<u32> {
+        let rotation = (bits >> 8) as u32;
+        Ok(((bits & 0xFF) as u32).rotate_right(2 * rotation))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode)]
+#[bitpattern("1110001110100000[rd:0-3][imm:0-11]")]
+struct MovImm {
+    rd: u8,
+    #[bitpattern(with = rotated_imm)]
+    imm: u32,
+}
+
+#[test]
+fn test_decode_with_codec() -> Result<()> {
+    let encoded: u32 = MovImm { rd: 0, imm: 0xFF000000 }.bits_into()?;
+    assert_eq!(encoded, 0xE3A004FF);
+    assert_eq!(MovImm::from_bits(&encoded)?, MovImm { rd: 0, imm: 0xFF000000 });
+
+    for imm in [0, 0x2A, 0x3FC, 0xC000003F] {
+        let encoded: u32 = MovImm { rd: 5, imm }.bits_into()?;
+        assert_eq!(MovImm::from_bits(&encoded)?, MovImm { rd: 5, imm });
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_encode_with_codec_error() {
+    let result: Result<u32> = MovImm { rd: 0, imm: 0x101 }.bits_into();
+    match result {
+        Err(Error::WriteFailed { cause, .. }) => {
+            assert_eq!(*cause, Error::invalid_field_value("imm", "0x101 isn't an 8-bit value rotated by an even amount"));
+        },
+        other => panic!("Expected an invalid field value, got {:?}", other),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode, BitMask)]
+enum Rv {
+    #[bitpattern("0000000000000001")]
+    CNop,
+    #[bitpattern("010[imm:5:s][rd:0-4][imm:0-4]01")]
+    CLi { rd: u8, imm: i8 },
+    #[bitpattern("[imm:0-11:s][rs1:0-4]000[rd:0-4]0010011")]
+    Addi { rd: u8, rs1: u8, imm: i16 },
+}
+
+#[test]
+fn test_decode_length() -> Result<()> {
+    let li: u16 = Rv::CLi { rd: 10, imm: -1 }.bits_into()?;
+    let addi: u32 = Rv::Addi { rd: 1, rs1: 2, imm: 100 }.bits_into()?;
+    let mut bytes = vec![];
+    bytes.extend(li.to_le_bytes());
+    bytes.extend(addi.to_le_bytes());
+    bytes.extend(0x0001u16.to_le_bytes());
+
+    let source = LittleEndian::<&[u8]>::new(&bytes[..]);
+    assert_eq!(Rv::decode_length(&source, 0, 64)?, 16);
+    assert_eq!(Rv::decode_length(&source, 16, 48)?, 32);
+    assert_eq!(Rv::decode_length(&source, 48, 16)?, 16);
+    assert_eq!(Rv::decode_with_length(&source, 16, 48)?, (Rv::Addi { rd: 1, rs1: 2, imm: 100 }, 32));
+    assert_eq!(Rv::decode_length(&source, 16, 16), Err(Error::no_matching_pattern("Rv", 16)));
+
+    let mut decoder = Decoder::<Rv>::new(&bytes);
+    assert_eq!(decoder.next(), Some(Ok(Rv::CLi { rd: 10, imm: -1 })));
+    assert_eq!(decoder.position(), 16);
+    assert_eq!(decoder.next(), Some(Ok(Rv::Addi { rd: 1, rs1: 2, imm: 100 })));
+    assert_eq!(decoder.position(), 48);
+    assert_eq!(decoder.next(), Some(Ok(Rv::CNop)));
+    assert_eq!(decoder.next(), None);
+
+    Ok(())
+}
+
+#[test]
+fn test_decoder_stops_on_error() {
+    let bytes = [0x93, 0x00, 0x41, 0x06, 0x93, 0x00];
+    let decoded: Vec<Result<Rv>> = Decoder::new(&bytes).collect();
+    assert_eq!(decoded, vec![
+        Ok(Rv::Addi { rd: 1, rs1: 2, imm: 100 }),
+        Err(Error::no_matching_pattern("Rv", 16)),
+    ]);
+}
+
+const _: () = assert!(Op::BITS == 16);
+const _: () = assert!(Jump::BITS == 32);
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode, FixedBitSize)]
+enum RType {
+    #[bitpattern("
+        7'h00 | [rs2:0-4] | [rs1:0-4] | 3'o0 | [rd:0-4] | 7'h33    // add
+    ")]
+    Add { rd: u8, rs1: u8, rs2: u8 },
+    #[bitpattern("0100000_[rs2:0-4]_[rs1:0-4]_000_[rd:0-4]_0110011")]
+    Sub { rd: u8, rs1: u8, rs2: u8 },
+    #[bitpattern("
+        // funct7, then the registers
+        7'b0000001 [rs2:0-4] [rs1:0-4]
+        3'd0 [rd:0-4] 7'h33
+    ")]
+    Mul { rd: u8, rs1: u8, rs2: u8 },
+}
+
+const _: () = assert!(RType::BITS == 32);
+
+#[test]
+fn test_decode_readable_pattern() -> Result<()> {
+    let encoded: u32 = RType::Add { rd: 1, rs1: 2, rs2: 3 }.bits_into()?;
+    assert_eq!(encoded, 0x003100B3);
+    let encoded: u32 = RType::Sub { rd: 1, rs1: 2, rs2: 3 }.bits_into()?;
+    assert_eq!(encoded, 0x403100B3);
+    let encoded: u32 = RType::Mul { rd: 1, rs1: 2, rs2: 3 }.bits_into()?;
+    assert_eq!(encoded, 0x023100B3);
+
+    assert_eq!(RType::from_bits(&0x003100B3u32)?, RType::Add { rd: 1, rs1: 2, rs2: 3 });
+    assert_eq!(RType::from_bits(&0x403100B3u32)?, RType::Sub { rd: 1, rs1: 2, rs2: 3 });
+    assert_eq!(RType::from_bits(&0x023100B3u32)?, RType::Mul { rd: 1, rs1: 2, rs2: 3 });
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode, FixedBitSize)]
+enum Grouped {
+    #[bitpattern("8'b1010_1010 | [x:0-3]")]
+    Byte { x: u8 },
+    #[bitpattern("12'h5_55")]
+    Short,
+}
+
+const _: () = assert!(Grouped::BITS == 12);
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode, FixedBitSize)]
+#[bitpattern("7'h00_0000000_[x:0-4]")]
+struct GroupedPrefix {
+    x: u8,
+}
+
+const _: () = assert!(GroupedPrefix::BITS == 19);
+
+#[test]
+fn test_decode_grouped_literal_digits() -> Result<()> {
+    assert_eq!(Grouped::Byte { x: 0b0110 }.size(), 12);
+    let encoded: u16 = Grouped::Byte { x: 0b0110 }.bits_into()?;
+    assert_eq!(encoded, 0b1010_1010_0110);
+    assert_eq!(Grouped::from_bits(&LittleEndian::<&[u8]>::with_bits(&[0b1010_0110, 0b1010], 12)?)?, Grouped::Byte { x: 0b0110 });
+
+    assert_eq!(Grouped::Short.size(), 12);
+    let encoded: u16 = Grouped::Short.bits_into()?;
+    assert_eq!(encoded, 0x555);
+
+    let encoded: u32 = GroupedPrefix { x: 0b10110 }.bits_into()?;
+    assert_eq!(encoded, 0b10110);
+    assert_eq!(GroupedPrefix::from_bits(&encoded)?, GroupedPrefix { x: 0b10110 });
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode, BitMask, FixedBitSize)]
+#[bitformat(
+    RType = "{funct7:7} [rs2:0-4] [rs1:0-4] {funct3:3} [rd:0-4] {opcode:7}",
+    IType = "[imm:0-11:s] [rs1:0-4] {funct3:3} [rd:0-4] {opcode:7}",
+)]
+enum Alu32 {
+    #[bitpattern(format = RType, funct7 = "0000000", funct3 = "000", opcode = "0110011")]
+    Add { rd: u8, rs1: u8, rs2: u8 },
+    #[bitpattern(format = RType, funct7 = "7'h20", funct3 = "000", opcode = "7'h33")]
+    Sub { rd: u8, rs1: u8, rs2: u8 },
+    #[bitpattern(format = RType, funct7 = "0000000", funct3 = "111", opcode = "0110011")]
+    And { rd: u8, rs1: u8, rs2: u8 },
+    #[bitpattern(format = IType, funct3 = "000", opcode = "0010011")]
+    Addi { rd: u8, rs1: u8, imm: i16 },
+    #[bitpattern(format = IType, funct3 = "111", opcode = "0010011", imm = mask)]
+    Andi { rd: u8, rs1: u8, mask: i16 },
+}
+
+#[test]
+fn test_decode_format() -> Result<()> {
+    assert_eq!(Alu32::from_bits(&0x003100B3u32)?, Alu32::Add { rd: 1, rs1: 2, rs2: 3 });
+    assert_eq!(Alu32::from_bits(&0x403100B3u32)?, Alu32::Sub { rd: 1, rs1: 2, rs2: 3 });
+    assert_eq!(Alu32::from_bits(&0x003170B3u32)?, Alu32::And { rd: 1, rs1: 2, rs2: 3 });
+    assert_eq!(Alu32::from_bits(&0xFFF10093u32)?, Alu32::Addi { rd: 1, rs1: 2, imm: -1 });
+    assert_eq!(Alu32::from_bits(&0x0FF17093u32)?, Alu32::Andi { rd: 1, rs1: 2, mask: 255 });
+
+    let encoded: u32 = Alu32::Andi { rd: 1, rs1: 2, mask: 255 }.bits_into()?;
+    assert_eq!(encoded, 0x0FF17093);
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode, BitLayout, FixedBitSize)]
+enum Arm {
+    #[bitpattern("1110 0001101 0 (0)(0)(0)(0) [rd:0-3] 00000000 [rm:0-3]")]
+    Mov { rd: u8, rm: u8 },
+    #[bitpattern("1110 00010110 (1)(1)(1)(1) [rd:0-3] (1)(1)(1)(1) 0001 [rm:0-3]")]
+    Clz { rd: u8, rm: u8 },
+}
+
+#[test]
+fn test_decode_reserved() -> Result<()> {
+    let encoded: u32 = Arm::Mov { rd: 0, rm: 1 }.bits_into()?;
+    assert_eq!(encoded, 0xE1A00001);
+    let encoded: u32 = Arm::Clz { rd: 0, rm: 1 }.bits_into()?;
+    assert_eq!(encoded, 0xE16F0F11);
+
+    assert_eq!(Arm::from_bits(&0xE1A00001u32)?, Arm::Mov { rd: 0, rm: 1 });
+    assert_eq!(Arm::from_bits(&0xE16F0F11u32)?, Arm::Clz { rd: 0, rm: 1 });
+    assert_eq!(Arm::from_bits(&0xE1A50001u32), Err(Error::unpredictable("Arm::Mov")));
+    assert_eq!(Arm::from_bits(&0xE1600F11u32), Err(Error::unpredictable("Arm::Clz")));
+    assert_eq!(Arm::from_bits(&0xE1A00101u32), Err(Error::no_matching_pattern("Arm", 32)));
+    assert_eq!(Arm::decode_length(&0xE1A50001u32, 0, 32)?, 32);
+
+    Ok(())
+}
+
+#[test]
+fn test_mask_and_match() {
+    assert_eq!(Alu32::MASK_ADD, 0xFE00707F);
+    assert_eq!(Alu32::MATCH_ADD, 0x00000033);
+    assert_eq!(Alu32::MATCH_SUB, 0x40000033);
+    assert_eq!(Alu32::MASK_ADDI, 0x0000707F);
+    assert_eq!(Alu32::MATCH_ANDI, 0x00007013);
+    assert_eq!(Alu32::PATTERNS.len(), 5);
+    assert_eq!(Alu32::PATTERNS[1], ("Sub", 0xFE00707F, 0x40000033));
+
+    let matching: Vec<&str> = Alu32::PATTERNS.iter()
+        .filter(|(_, mask, value)| 0x403100B3u32 & mask == *value)
+        .map(|(name, _, _)| *name)
+        .collect();
+    assert_eq!(matching, vec!["Sub"]);
+
+    let mask: u32 = Rv::MASK_C_LI;
+    assert_eq!(mask, 0xE003);
+    assert_eq!(Rv::MATCH_C_LI, 0x4001);
+    assert_eq!(Rv::MASK_C_NOP, 0xFFFF);
+}
+
+#[test]
+fn test_layout_table() {
+    assert_eq!(
+        Jump::LAYOUT_JAL,
+        "| 31 | 30:21 | 20 | 19:12 | 11:7 | 6:0 |\n\
+         | --- | --- | --- | --- | --- | --- |\n\
+         | offset[20] (signed) | offset[10:1] | offset[11] | offset[19:12] | rd[4:0] | `1101111` |\n"
+    );
+    assert_eq!(
+        Arm::LAYOUT_MOV,
+        "| 31:28 | 27:21 | 20 | 19:16 | 15:12 | 11:4 | 3:0 |\n\
+         | --- | --- | --- | --- | --- | --- | --- |\n\
+         | `1110` | `0001101` | `0` | `(0)(0)(0)(0)` | rd[3:0] | `00000000` | rm[3:0] |\n"
+    );
+    assert_eq!(
+        Fence::LAYOUT_FENCE_I,
+        "| 31:15 | 14:12 | 11:7 | 6:0 |\n\
+         | --- | --- | --- | --- |\n\
+         | `xxxxxxxxxxxxxxxxx` | `001` | `xxxxx` | `0001111` |\n"
+    );
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode, FixedBitSize)]
+enum Positioned {
+    #[fixed(0..=6, 0b0110011)]
+    #[fixed(12..=14, 0)]
+    #[fixed(25..=31, 0)]
+    Add {
+        #[bits(7..=11)]
+        rd: u8,
+        #[bits(15..=19)]
+        rs1: u8,
+        #[bits(20..=24)]
+        rs2: u8,
+    },
+    #[fixed(0..=6, 0b0110011)]
+    #[fixed(12..=14, 0)]
+    #[fixed(25..=31, 0b0100000)]
+    Sub {
+        #[bits(7..=11)]
+        rd: u8,
+        #[bits(15..=19)]
+        rs1: u8,
+        #[bits(20..=24)]
+        rs2: u8,
+    },
+    #[fixed(0..=6, 0x13)]
+    #[fixed(12..=14, 0)]
+    Addi {
+        #[bits(7..=11)]
+        rd: u8,
+        #[bits(15..=19)]
+        rs1: u8,
+        #[bits(20..=31, signed)]
+        imm: i16,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode)]
+#[fixed(0..=3, 0xF)]
+struct Gap {
+    #[bits(8..=11)]
+    value: u8,
+}
+
+#[test]
+fn test_decode_positioned_fields() -> Result<()> {
+    assert_eq!(Positioned::from_bits(&0x003100B3u32)?, Positioned::Add { rd: 1, rs1: 2, rs2: 3 });
+    assert_eq!(Positioned::from_bits(&0x403100B3u32)?, Positioned::Sub { rd: 1, rs1: 2, rs2: 3 });
+    assert_eq!(Positioned::from_bits(&0xFFF10093u32)?, Positioned::Addi { rd: 1, rs1: 2, imm: -1 });
+
+    let encoded: u32 = Positioned::Sub { rd: 1, rs1: 2, rs2: 3 }.bits_into()?;
+    assert_eq!(encoded, 0x403100B3);
+    let encoded: u32 = Positioned::Addi { rd: 1, rs1: 2, imm: -1 }.bits_into()?;
+    assert_eq!(encoded, 0xFFF10093);
+
+    let gap = Gap { value: 0xA };
+    assert_eq!(gap.size(), 12);
+    let encoded: u16 = gap.bits_into()?;
+    assert_eq!(encoded, 0xA0F);
+    assert_eq!(Gap::decode(&0x5A3Fu16, 0, 12)?, gap);
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode, BitLayout)]
+enum Power {
+    #[bitpattern("011111 [rs:0-4] [spr:5-9] [spr:0-4] 0111010011 0", numbering = msb0)]
+    Mtspr { rs: u8, spr: u16 },
+    #[fixed(0..=5, 14)]
+    #[bits(msb0)]
+    Addi {
+        #[bits(6..=10)]
+        rd: u8,
+        #[bits(11..=15)]
+        ra: u8,
+        #[bits(16..=31, signed)]
+        simm: i16,
+    },
+    #[bitpattern("010000 [bo:0-4] [bi:0-4] [bd:0-13:s] 0 0", numbering = msb0)]
+    Bc {
+        bo: u8,
+        bi: u8,
+        #[bits(16)]
+        bd: i16,
+    },
+    #[fixed(0..=5, 31)]
+    #[fixed(21..=30, 598)]
+    #[bits(msb0, 32)]
+    Sync,
+}
+
+#[test]
+fn test_msb0_numbering() -> Result<()> {
+    let mtlr = Power::Mtspr { rs: 0, spr: 8 };
+    assert_eq!(Power::from_bits(&0x7C0803A6u32)?, mtlr);
+    let encoded: u32 = mtlr.bits_into()?;
+    assert_eq!(encoded, 0x7C0803A6);
+
+    let addi = Power::Addi { rd: 3, ra: 1, simm: -8 };
+    assert_eq!(Power::from_bits(&0x3861FFF8u32)?, addi);
+    let encoded: u32 = addi.bits_into()?;
+    assert_eq!(encoded, 0x3861FFF8);
+
+    let bc = Power::Bc { bo: 12, bi: 0, bd: -8 };
+    assert_eq!(Power::from_bits(&0x4180FFF8u32)?, bc);
+    let encoded: u32 = bc.bits_into()?;
+    assert_eq!(encoded, 0x4180FFF8);
+
+    assert_eq!(Power::Sync.size(), 32);
+    assert_eq!(Power::from_bits(&0x7C0004ACu32)?, Power::Sync);
+    let encoded: u32 = Power::Sync.bits_into()?;
+    assert_eq!(encoded, 0x7C0004AC);
+
+    assert_eq!(
+        Power::LAYOUT_MTSPR,
+        "| 0:5 | 6:10 | 11:15 | 16:20 | 21:30 | 31 |\n\
+         | --- | --- | --- | --- | --- | --- |\n\
+         | `011111` | rs[0:4] | spr[5:9] | spr[0:4] | `0111010011` | `0` |\n"
+    );
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode)]
+#[bitpattern("0110 [opcode:0-3]")]
+struct Annotated {
+    opcode: u8,
+    #[bitpattern(skip)]
+    label: String,
+    #[bitpattern(skip, default = 4)]
+    width: usize,
+}
+
+#[test]
+fn test_skipped_fields() -> Result<()> {
+    let decoded = Annotated::from_bits(&0x6Au8)?;
+    assert_eq!(decoded, Annotated { opcode: 0xA, label: String::new(), width: 4 });
+
+    let annotated = Annotated { opcode: 0x3, label: "loop".to_string(), width: 8 };
+    assert_eq!(annotated.size(), 8);
+    let encoded: u8 = annotated.bits_into()?;
+    assert_eq!(encoded, 0x63);
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode, BitMask)]
+#[bitpattern("[version:0-3] [len:0-3] [data:0-7]", version = 4u8, len = data.count_ones() as u8)]
+struct Versioned {
+    data: u8,
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode)]
+enum Marked {
+    #[bitpattern("[tag:0-3] [value:0-3]", tag = -1i8)]
+    Negative { value: u8 },
+    #[bitpattern("[tag:0-3] [value:0-3]", tag = 1u8, value = self.magnitude())]
+    Positive {
+        #[bitpattern(skip)]
+        magnitude: u8,
+    },
+}
+
+impl Marked {
+    fn magnitude(&self) -> u8 {
+        match self {
+            Marked::Negative { value } => *value,
+            Marked::Positive { magnitude } => *magnitude,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode)]
+#[bitpattern(
+    "[total:0-7] [data:0-7]",
+    total = { let data = data.wrapping_mul(2); [1u8, 2].iter().fold(data, |data, step| data + step) }
+)]
+struct Shadowed {
+    data: u8,
+}
+
+#[test]
+fn test_computed_mappings() -> Result<()> {
+    assert_eq!(Versioned::MASK, 0xF000);
+    assert_eq!(Versioned::MATCH, 0x4000);
+
+    let versioned = Versioned { data: 0b1011 };
+    let encoded: u16 = versioned.bits_into()?;
+    assert_eq!(encoded, 0x430B);
+    assert_eq!(Versioned::from_bits(&0x4F0Bu16)?, versioned);
+    assert!(Versioned::from_bits(&0x530Bu16).is_err());
+
+    let encoded: u8 = Marked::Positive { magnitude: 5 }.bits_into()?;
+    assert_eq!(encoded, 0x15);
+    assert_eq!(Marked::from_bits(&0xF3u8)?, Marked::Negative { value: 3 });
+    assert_eq!(Marked::from_bits(&0x15u8)?, Marked::Positive { magnitude: 0 });
+
+    let encoded: u16 = Shadowed { data: 5 }.bits_into()?;
+    assert_eq!(encoded, 0x0D05);
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq, BitSource, BitDecode, FixedBitSize)]
+enum FromFile {
+    #[bitpattern(file = "tests/isa/rv32.opcodes", entry = "add")]
+    Add { rd: u8, rs1: u8, rs2: u8 },
+    #[bitpattern(file = "tests/isa/rv32.opcodes", entry = "sub")]
+    Sub { rd: u8, rs1: u8, rs2: u8 },
+    #[bitpattern(file = "tests/isa/rv32.opcodes", entry = "addi", imm12 = imm)]
+    Addi { rd: u8, rs1: u8, imm: u16 },
+}
+
+#[test]
+fn test_patterns_from_file() -> Result<()> {
+    assert_eq!(FromFile::BITS, 32);
+    assert_eq!(FromFile::from_bits(&0x003100B3u32)?, FromFile::Add { rd: 1, rs1: 2, rs2: 3 });
+    assert_eq!(FromFile::from_bits(&0x403100B3u32)?, FromFile::Sub { rd: 1, rs1: 2, rs2: 3 });
+    assert_eq!(FromFile::from_bits(&0xFFF10093u32)?, FromFile::Addi { rd: 1, rs1: 2, imm: 0xFFF });
+
+    let encoded: u32 = FromFile::Sub { rd: 1, rs1: 2, rs2: 3 }.bits_into()?;
+    assert_eq!(encoded, 0x403100B3);
+
+    Ok(())
+}
@@ -0,0 +1,176 @@
+use decree::{BitSource, BitSink, FixedBitSize, Result};
+
+#[derive(Debug, Default, PartialEq, Eq, BitSource, BitSink)]
+#[bitpattern("111[x:1]111[x:3]11[x:4]11[y:4-9]1[z:12]", z = y)]
+struct Foo {
+    x: i8,
+    y: u64,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, BitSource, BitSink)]
+#[bitpattern("1010[0:0-3]11[1:0-5]0[2:0]")]
+struct Bar(u8, u16, u32);
+
+#[derive(Debug, Default, PartialEq, Eq, BitSource, BitSink)]
+#[bitpattern("10101101")]
+struct Baz;
+
+fn decode<T: BitSink + Default>(value: impl BitSource) -> Result<T> {
+    let mut sink = T::default();
+    let len = BitSink::size(&sink).unwrap();
+    value.write(&mut sink, 0, len, 0)?;
+    Ok(sink)
+}
+
+#[test]
+fn test_struct_foo() -> Result<()> {
+    let foo: Foo = decode(0b111011101101100000010u64)?;
+    assert_eq!(foo, Foo { x: 0, y: 0 });
+
+    let foo: Foo = decode(0b111111111111111111111u64)?;
+    assert_eq!(foo, Foo { x: 0b11010, y: 0b1001111110000 });
+
+    Ok(())
+}
+
+#[test]
+fn test_struct_foo_ignores_literals() -> Result<()> {
+    let foo: Foo = decode(0b000100010010011111101u64)?;
+    assert_eq!(foo, Foo { x: 0b11010, y: 0b1001111110000 });
+
+    Ok(())
+}
+
+#[test]
+fn test_struct_foo_partial() -> Result<()> {
+    let mut foo = Foo::default();
+    let written = 0b1111111u8.write(&mut foo, 0, 7, 2)?;
+    assert_eq!(written, 7);
+    assert_eq!(foo, Foo { x: 0, y: 0b1111110000 });
+
+    Ok(())
+}
+
+#[test]
+fn test_struct_bar_roundtrip() -> Result<()> {
+    let bar = Bar(0b1001, 0b101101, 1);
+
+    let encoded: u32 = bar.bits_into()?;
+    assert_eq!(encoded, 0b101010011110110101u32);
+
+    let decoded: Bar = decode(encoded)?;
+    assert_eq!(decoded, bar);
+
+    Ok(())
+}
+
+#[test]
+fn test_struct_size() {
+    assert_eq!(BitSink::size(&Foo::default()), Some(21));
+    assert_eq!(BitSink::size(&Bar::default()), Some(18));
+    assert_eq!(BitSink::size(&Baz), Some(8));
+}
+
+#[test]
+fn test_struct_out_of_range() {
+    let mut foo = Foo::default();
+    let result = 0u32.write(&mut foo, 0, 22, 0);
+    assert!(result.is_err());
+}
+
+#[derive(Debug, Default, PartialEq, Eq, BitSource, BitSink)]
+#[bitpattern("[x:4-7:s]11[x:0-3]")]
+struct Signed {
+    x: i16,
+}
+
+#[test]
+fn test_struct_signed() -> Result<()> {
+    let signed: Signed = decode(0b1110110001u16)?;
+    assert_eq!(signed, Signed { x: -31 });
+
+    let signed: Signed = decode(0b0110110001u16)?;
+    assert_eq!(signed, Signed { x: 0b01100001 });
+
+    Ok(())
+}
+
+#[derive(Debug, Default, PartialEq, Eq, BitSource, BitSink)]
+#[bitpattern("111[x:1]111[x:3]11[x:4]11[y:4-9]1[z:12]111[z:17-30]", x = 0, y = 1, z = 2)]
+struct Scattered(i8, u16, u32);
+
+#[test]
+fn test_struct_scattered() -> Result<()> {
+    let scattered = Scattered(0b11010, 0b1111110000, 0b1111111111111100001000000000000);
+    let encoded: u64 = scattered.bits_into()?;
+    assert_eq!(encoded, 0b11111111111111111111111111111111111111u64);
+
+    let decoded: Scattered = decode(encoded)?;
+    assert_eq!(decoded, scattered);
+
+    let decoded: Scattered = decode(0u64)?;
+    assert_eq!(decoded, Scattered(0, 0, 0));
+
+    Ok(())
+}
+
+#[derive(Debug, Default, PartialEq, Eq, BitSource, BitSink)]
+struct Ipv4Start {
+    #[bits(4)]
+    version: u8,
+    #[bits(4)]
+    ihl: u8,
+    #[bits(6)]
+    dscp: u8,
+    #[bits(2)]
+    ecn: u8,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, BitSource, BitSink)]
+#[bits(lsb_first)]
+struct Status(#[bits(1)] u8, #[bits(3, signed)] i8, #[bits(4)] u8);
+
+#[test]
+fn test_struct_sequential_msb_first() -> Result<()> {
+    let header = Ipv4Start { version: 4, ihl: 5, dscp: 0b101110, ecn: 1 };
+    assert_eq!(BitSource::size(&header), 16);
+    let encoded: u16 = header.bits_into()?;
+    assert_eq!(encoded, 0x45B9);
+
+    let decoded: Ipv4Start = decode(0x45B9u16)?;
+    assert_eq!(decoded, header);
+
+    Ok(())
+}
+
+#[test]
+fn test_struct_sequential_lsb_first() -> Result<()> {
+    let status = Status(1, -2, 0b1010);
+    let encoded: u8 = status.bits_into()?;
+    assert_eq!(encoded, 0b10101101);
+
+    let decoded: Status = decode(0b10101101u8)?;
+    assert_eq!(decoded, status);
+
+    Ok(())
+}
+
+#[derive(Debug, Default, PartialEq, Eq, BitSource, BitSink)]
+struct Pair<T> where T: FixedBitSize {
+    #[bits(12)]
+    high: T,
+    #[bits(4)]
+    low: u8,
+}
+
+#[test]
+fn test_struct_sequential_generic() -> Result<()> {
+    let pair = Pair { high: 0xABCu16, low: 0xD };
+    let encoded: u16 = pair.bits_into()?;
+    assert_eq!(encoded, 0xABCD);
+
+    let decoded: Pair<u16> = decode(0xABCDu16)?;
+    assert_eq!(decoded, pair);
+
+    Ok(())
+}
@@ -1,7 +1,7 @@
-use decree::{BitSource, Result};
+use decree::{BitSource, FixedBitSize, Result};
 
 #[derive(Debug, BitSource)]
-enum InstrGen<T> where T: core::fmt::Debug {
+enum InstrGen<T> where T: core::fmt::Debug + FixedBitSize {
     #[bitpattern("111[x:1]111[x:3]11[x:4]11[y:4-9]1[z:12]", z = y)]
     Foo { x: i8, y: T },
     #[bitpattern(
@@ -68,14 +68,71 @@ fn test_instr_foo_ones() -> Result<()> {
     Ok(())
 }
 
-#[derive(Debug, BitSource)]
+#[test]
+fn test_instr_bar() -> Result<()> {
+    let instr = Instr::Bar(0, 0, 0);
+
+    let converted: u64 = instr.bits_into()?;
+    assert_eq!(converted, 0b11101110110110000001011100000000000000u64);
+
+    let instr = Instr::Bar(-1, 0xFFFF, 0xFFFFFFFF);
+
+    let converted: u64 = instr.bits_into()?;
+    assert_eq!(converted, 0b11111111111111111111111111111111111111u64);
+
+    Ok(())
+}
+
+#[test]
+fn test_instr_baz() -> Result<()> {
+    let converted: u32 = Instr::Baz.bits_into()?;
+    assert_eq!(converted, 0b11101110011001101001110u32);
+
+    let converted: u32 = InstrGen::<u16>::Baz.bits_into()?;
+    assert_eq!(converted, 0b11101110011001101001110u32);
+
+    Ok(())
+}
+
+#[test]
+fn test_instr_gen() -> Result<()> {
+    let instr = InstrGen::Foo { x: 0, y: 0u64 };
+
+    let converted: u64 = instr.bits_into()?;
+    assert_eq!(converted, 0b111011101101100000010u64);
+
+    let instr = InstrGen::<u64>::Bar(0, 0, 0);
+
+    let converted: u64 = instr.bits_into()?;
+    assert_eq!(converted, 0b11101110110110000001011100000000000000u64);
+
+    Ok(())
+}
+
+#[derive(Debug, BitSource, FixedBitSize)]
 #[bitpattern("1001110011000")]
 struct Foo;
 
-#[derive(Debug, BitSource)]
+#[derive(Debug, BitSource, FixedBitSize)]
 #[bitpattern("10101101")]
 struct Bar;
 
+const _: () = assert!(Foo::BITS == 13);
+
+#[test]
+fn test_fixed_bit_size() -> Result<()> {
+    assert_eq!(Bar::BITS, 8);
+    assert_eq!(<u32 as FixedBitSize>::BITS, 32);
+    assert_eq!(<i16 as FixedBitSize>::BITS, 16);
+
+    let mut bytes = [0u8; Foo::BITS.div_ceil(8)];
+    let mut sink = decree::LittleEndian::<&[u8]>::with_bits(&mut bytes[..], Foo::BITS)?;
+    Foo.write_to(&mut sink, 0)?;
+    assert_eq!(bytes, [0b10011000, 0b00010011]);
+
+    Ok(())
+}
+
 #[test]
 fn test_empty_struct_13bit_u128() -> Result<()> {
     let foo = Foo;
@@ -117,6 +174,7 @@ fn test_empty_struct_13bit_u16() -> Result<()> {
 }
 
 #[test]
+#[allow(clippy::single_match)]
 fn test_empty_struct_13bit_u8() -> Result<()> {
     let foo = Foo;
 
@@ -170,6 +228,7 @@ fn test_empty_struct_13bit_i16() -> Result<()> {
 }
 
 #[test]
+#[allow(clippy::single_match)]
 fn test_empty_struct_13bit_i8() -> Result<()> {
     let foo = Foo;
 
@@ -281,3 +340,16 @@ fn test_empty_struct_8bit_i8() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_instr_foo_partial_token() -> Result<()> {
+    let instr = Instr::Foo { x: 0, y: 0 };
+
+    let mut output = 0u32;
+    let written = instr.write(&mut output, 3, 7, 0)?;
+
+    assert_eq!(written, 7);
+    assert_eq!(output, 0b1100000u32);
+
+    Ok(())
+}
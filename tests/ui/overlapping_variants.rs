@@ -0,0 +1,11 @@
+use decree::BitDecode;
+
+#[derive(BitDecode)]
+enum Instr {
+    #[bitpattern("0101[x:0-3]")]
+    Load { x: u8 },
+    #[bitpattern("01[y:0-5]")]
+    Store { y: u8 },
+}
+
+fn main() {}
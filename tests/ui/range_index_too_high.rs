@@ -0,0 +1,9 @@
+use decree::BitDecode;
+
+#[derive(BitDecode)]
+#[bitpattern("[x:0-18446744073709551614]")]
+struct Huge {
+    x: u8,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use decree::BitDecode;
+
+#[derive(BitDecode)]
+#[bitpattern("0110 [x:0-11]")]
+struct Narrow {
+    x: u8,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use decree::BitDecode;
+
+#[derive(BitDecode)]
+enum FromFile {
+    #[bitpattern(file = "../../../../tests/isa/rv32.opcodes", entry = "add", args = "../../../../tests/isa/missing.csv")]
+    Add { rd: u8, rs1: u8, rs2: u8 },
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use decree::BitSource;
+
+#[derive(BitSource)]
+#[bitpattern("0110 [x:0-3]")]
+struct Wrapper<T> {
+    x: T,
+}
+
+fn main() {}
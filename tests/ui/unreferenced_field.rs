@@ -0,0 +1,10 @@
+use decree::BitSource;
+
+#[derive(BitSource)]
+#[bitpattern("0110 [opcode:0-3]")]
+struct Annotated {
+    opcode: u8,
+    label: String,
+}
+
+fn main() {}